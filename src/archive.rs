@@ -0,0 +1,133 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use rusqlite::{params, Connection};
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path
+};
+
+use crate::{
+    config::Problem,
+    contest::{query_contests, save_contest, Contest},
+    job::{query_jobs, save_job, JobContent},
+    migration,
+    timestamp,
+    problem::{query_problems, save_problem},
+    user::{query_users, save_user, User}
+};
+
+/// one line of an archive, `{"table": "user", "row": {...}}`
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "table", content = "row", rename_all = "snake_case")]
+enum Record {
+    /// the first line
+    Header {
+        schema_version: u32,
+        #[serde(with = "timestamp")]
+        exported_time: DateTime<Utc>
+    },
+    User(User),
+    Problem(Problem),
+    Contest(Contest),
+    Job(JobContent)
+}
+
+fn db_error(err: rusqlite::Error) -> String {
+    format!("Database error: {}", err)
+}
+
+/// write users, problems, contests and jobs with their cases to a JSON Lines file,
+/// the test data of problems is not included and must be copied with the
+/// data directory, return the number of rows
+pub fn export(conn: &Connection, path: &str) -> Result<usize, String> {
+    // one snapshot, the server may be running
+    let tx = conn.unchecked_transaction().map_err(db_error)?;
+    let mut records = vec![Record::Header {
+        schema_version: migration::current_version(&tx).map_err(db_error)?,
+        exported_time: timestamp::now()
+    }];
+    records.extend(query_users(&tx).map_err(db_error)?.into_iter().map(Record::User));
+    records.extend(query_problems(&tx).map_err(db_error)?.into_iter().map(Record::Problem));
+    records.extend(query_contests(&tx).map_err(db_error)?.into_iter().map(Record::Contest));
+    records.extend(query_jobs(&tx).map_err(db_error)?.into_iter().map(Record::Job));
+    tx.commit().map_err(db_error)?;
+
+    let file = File::create(path).map_err(|err| format!("Failed to create {}: {}", path, err))?;
+    let mut writer = BufWriter::new(file);
+    for record in &records {
+        serde_json::to_writer(&mut writer, record)
+            .map_err(|err| format!("Failed to write {}: {}", path, err))?;
+        writer.write_all(b"\n").map_err(|err| format!("Failed to write {}: {}", path, err))?;
+    }
+    writer.flush().map_err(|err| format!("Failed to write {}: {}", path, err))?;
+    Ok(records.len() - 1)
+}
+
+/// load an archive written by `export` into a database without users, contests
+/// or jobs, nothing is kept if any line fails or a case file of a problem is
+/// missing, return the number of rows
+pub fn restore(conn: &mut Connection, path: &str) -> Result<usize, String> {
+    let file = File::open(path).map_err(|err| format!("Failed to read {}: {}", path, err))?;
+    let tx = conn.transaction().map_err(db_error)?;
+    let used: usize = tx.query_row(
+        "SELECT (SELECT COUNT(*) FROM users) + (SELECT COUNT(*) FROM contests) + (SELECT COUNT(*) FROM jobs)",
+        [],
+        |row| row.get(0)
+    ).map_err(db_error)?;
+    if used > 0 {
+        return Err("The database is not empty.".to_string());
+    }
+
+    let mut count = 0;
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|err| format!("Failed to read {}: {}", path, err))?;
+        let record: Record = serde_json::from_str(&line)
+            .map_err(|err| format!("Invalid line {}: {}", index + 1, err))?;
+        let saved = match record {
+            Record::Header { schema_version, .. } => {
+                if index > 0 {
+                    return Err(format!("Unexpected header on line {}.", index + 1));
+                }
+                if schema_version > migration::latest_version() {
+                    return Err("The archive was written by a newer version of the server.".to_string());
+                }
+                continue;
+            }
+            _ if index == 0 => return Err("Missing the archive header.".to_string()),
+            Record::User(user) => save_user(&tx, &user),
+            Record::Problem(problem) => {
+                check_case_files(&problem).map_err(|message| format!("Line {}: {}", index + 1, message))?;
+                save_problem(&tx, &problem)
+            }
+            Record::Contest(contest) => save_contest(&tx, &contest),
+            Record::Job(job) => save_job(&tx, &job)
+        };
+        saved.map_err(|err| format!("Failed to restore line {}: {}", index + 1, err))?;
+        count += 1;
+    }
+    tx.commit().map_err(db_error)?;
+    Ok(count)
+}
+
+/// the test data is not in the archive, it has to be in place already
+fn check_case_files(problem: &Problem) -> Result<(), String> {
+    for case in &problem.cases {
+        for file in [&case.input_file, &case.answer_file] {
+            if !Path::new(file).is_file() {
+                return Err(format!("Missing {} of problem {}.", file, problem.id));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// copy the database into a new file, safe while the server is running
+pub fn backup(conn: &Connection, path: &str) -> Result<(), String> {
+    if Path::new(path).exists() {
+        return Err(format!("{} already exists.", path));
+    }
+    conn.execute("VACUUM INTO ?", params![path])
+        .map_err(|err| format!("Failed to back up to {}: {}", path, err))?;
+    Ok(())
+}
@@ -0,0 +1,164 @@
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{self, OpenOptions},
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::SystemTime
+};
+
+use crate::config::{self, Language};
+
+// distinguish temporary files of concurrent stores
+static STORE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Content addressed cache of compiled programs
+pub struct CompileCache {
+    dir: PathBuf,
+    max_size: u64
+}
+
+impl CompileCache {
+    pub fn new(config: &config::CompileCache) -> CompileCache {
+        CompileCache {
+            dir: PathBuf::from(&config.path),
+            max_size: config.max_size()
+        }
+    }
+
+    /// the key covers everything that affects the artifact,
+    /// including the compiler so that an upgrade misses the cache
+    pub fn key(language: &Language, source_code: &[u8]) -> String {
+        let mut hasher = Sha256::new();
+        for arg in &language.command {
+            hasher.update(arg.as_bytes());
+            hasher.update([0u8]);
+        }
+        if let Some(compiler) = language.command.first().and_then(|x| compiler_identity(x)) {
+            hasher.update(compiler.as_bytes());
+        }
+        hasher.update([0u8]);
+        hasher.update(language.file_name.as_bytes());
+        hasher.update([0u8]);
+        hasher.update(source_code);
+        hasher
+            .finalize()
+            .iter()
+            .map(|x| format!("{:02x}", x))
+            .collect()
+    }
+
+    /// copy the cached artifact to `dest`, return false on miss
+    pub fn fetch(&self, key: &str, dest: &str) -> bool {
+        let path = self.dir.join(key);
+        if fs::copy(&path, dest).is_err() {
+            return false;
+        }
+        // mark as recently used
+        if let Ok(file) = OpenOptions::new().write(true).open(&path) {
+            file.set_modified(SystemTime::now()).ok();
+        }
+        log::info!("Compile cache hit {}", key);
+        true
+    }
+
+    /// save the artifact at `src`, then shrink the cache to its size limit
+    pub fn store(&self, key: &str, src: &str) {
+        if let Err(err) = fs::create_dir_all(&self.dir) {
+            log::warn!("Failed to create compile cache: {}", err);
+            return;
+        }
+        // write to a temporary name first so readers never see half a file
+        let count = STORE_COUNT.fetch_add(1, Ordering::Relaxed);
+        let tmp_path = self.dir.join(format!(".{}.{}.{}", key, std::process::id(), count));
+        let result = fs::copy(src, &tmp_path)
+            .and_then(|_| fs::rename(&tmp_path, self.dir.join(key)));
+        if let Err(err) = result {
+            log::warn!("Failed to store compile cache {}: {}", key, err);
+            fs::remove_file(&tmp_path).ok();
+            return;
+        }
+        self.evict();
+    }
+
+    /// remove least recently used artifacts until the cache fits
+    fn evict(&self) {
+        if self.max_size == 0 {
+            return;
+        }
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) => return
+        };
+        let mut artifacts: Vec<(SystemTime, u64, PathBuf)> = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+            .filter_map(|entry| {
+                let metadata = entry.metadata().ok()?;
+                Some((metadata.modified().ok()?, metadata.len(), entry.path()))
+            })
+            .collect();
+        let mut total_size: u64 = artifacts.iter().map(|x| x.1).sum();
+        artifacts.sort_by_key(|x| x.0);
+        for (_, size, path) in artifacts {
+            if total_size <= self.max_size {
+                break;
+            }
+            if fs::remove_file(&path).is_ok() {
+                total_size -= size;
+            }
+        }
+    }
+}
+
+/// the resolved path of the compiler with the modification time and size of
+/// the file, None if it cannot be found
+fn compiler_identity(command: &str) -> Option<String> {
+    let path = if command.contains('/') {
+        PathBuf::from(command)
+    } else {
+        std::env::split_paths(&std::env::var_os("PATH")?)
+            .map(|dir| dir.join(command))
+            .find(|path| path.is_file())?
+    };
+    let path = fs::canonicalize(path).ok()?;
+    let metadata = fs::metadata(&path).ok()?;
+    let modified = metadata
+        .modified()
+        .ok()?
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .ok()?;
+    Some(format!(
+        "{}\0{}\0{}",
+        path.display(),
+        modified.as_nanos(),
+        metadata.len()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn key_changes_with_the_compiler() {
+        let dir = PathBuf::from("target").join("cache_key_compiler");
+        fs::create_dir_all(&dir).unwrap();
+        let compiler = dir.join("cc");
+        fs::write(&compiler, "v1").unwrap();
+        let language = Language {
+            name: "C".to_string(),
+            file_name: "main.c".to_string(),
+            command: vec![compiler.to_str().unwrap().to_string(), "%INPUT%".to_string()]
+        };
+        let key = CompileCache::key(&language, b"int main() {}");
+        assert_eq!(key, CompileCache::key(&language, b"int main() {}"));
+        assert_ne!(key, CompileCache::key(&language, b"int main() { return 0; }"));
+
+        // an upgrade in place keeps the path but not the time
+        let file = OpenOptions::new().write(true).open(&compiler).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(60)).unwrap();
+        assert_ne!(key, CompileCache::key(&language, b"int main() {}"));
+    }
+}
@@ -1,43 +1,348 @@
 use serde::{Deserialize, Serialize};
-use clap::Parser;
+use actix_web::{http::header, HttpRequest};
+use clap::{Parser, Subcommand};
+use std::collections::HashSet;
 use std::fs::File;
-use std::io::Read;
+use std::io::{self, Read};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
 
 #[derive(Clone, Deserialize, Serialize)]
 pub struct Config {
     server: Server,
     pub problems: Vec<Problem>,
-    pub languages: Vec<Language>
+    pub languages: Vec<Language>,
+    #[serde(default)]
+    pub judge: Judge,
+    #[serde(default)]
+    pub database: Database
 }
 
 #[derive(Clone, Deserialize, Serialize)]
 struct Server {
-    bind_address: Option<String>,
-    bind_port: Option<i32>
+    /// one address or a list of addresses, 127.0.0.1 when missing
+    bind_address: Option<BindAddress>,
+    /// 12345 when missing
+    bind_port: Option<i32>,
+    /// path of a unix domain socket, when it is the only setting
+    /// no tcp address is bound
+    unix_socket: Option<String>,
+    /// requests with `Authorization: Bearer <admin_token>` are from admins
+    admin_token: Option<String>
 }
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+enum BindAddress {
+    One(String),
+    Many(Vec<String>)
+}
+
+impl Config {
+    /// tcp addresses to listen on
+    pub fn bind_addresses(&self) -> Vec<(String, u16)> {
+        let server = &self.server;
+        if server.unix_socket.is_some() && server.bind_address.is_none() && server.bind_port.is_none() {
+            return Vec::new();
+        }
+        let port = server.bind_port.unwrap_or(12345) as u16;
+        let addresses = match &server.bind_address {
+            Some(BindAddress::One(address)) => vec![address.clone()],
+            Some(BindAddress::Many(addresses)) => addresses.clone(),
+            None => vec!["127.0.0.1".to_string()]
+        };
+        addresses.into_iter().map(|address| (address, port)).collect()
+    }
+
+    pub fn unix_socket(&self) -> Option<String> {
+        self.server.unix_socket.clone()
+    }
+
+    pub fn is_admin(&self, request: &HttpRequest) -> bool {
+        let token = match &self.server.admin_token {
+            Some(token) => token,
+            None => return false
+        };
+        request.headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.strip_prefix("Bearer "))
+            .map(|x| x == token)
+            .unwrap_or(false)
+    }
+
+    /// check everything the judge relies on, return all the errors found
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if let Some(port) = self.server.bind_port {
+            if !(0..=65535).contains(&port) {
+                errors.push(format!("server: invalid bind_port {}", port));
+            }
+        }
+        if let Some(BindAddress::Many(addresses)) = &self.server.bind_address {
+            if addresses.is_empty() {
+                errors.push("server: bind_address is empty".to_string());
+            }
+        }
+        if !["sqlite", "memory"].contains(&self.database.backend().as_str()) {
+            errors.push(format!("database: unknown backend {}", self.database.backend()));
+        }
+        if self.judge.workers == Some(0) {
+            errors.push("judge: workers must be positive".to_string());
+        }
+        if let Some(cache) = &self.judge.compile_cache {
+            if cache.path.is_empty() {
+                errors.push("judge: compile_cache path is empty".to_string());
+            }
+        }
+
+        let mut problem_ids = HashSet::new();
+        for problem in &self.problems {
+            if !problem_ids.insert(problem.id) {
+                errors.push(format!("problem {}: duplicate id", problem.id));
+            }
+            problem.validate(&mut errors);
+        }
+
+        let mut language_names = HashSet::new();
+        for language in &self.languages {
+            if !language_names.insert(&language.name) {
+                errors.push(format!("language {}: duplicate name", language.name));
+            }
+            language.validate(&mut errors);
+        }
+        errors
+    }
+}
+
+/// the config used by handlers, replaced as a whole on reload,
+/// judge tasks keep the snapshot they started with
+pub struct SharedConfig {
+    cli: Cli,
+    current: RwLock<Arc<Config>>
+}
+
+impl SharedConfig {
+    pub fn new(cli: Cli, config: Config) -> SharedConfig {
+        SharedConfig {
+            cli,
+            current: RwLock::new(Arc::new(config))
+        }
+    }
+
+    pub fn get(&self) -> Arc<Config> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// read the config file again, the old config stays on any error,
+    /// server and worker settings only change on restart
+    pub fn reload(&self) -> Result<Arc<Config>, Vec<String>> {
+        let config = self.cli.init_config().map_err(|err| vec![err.to_string()])?;
+        let errors = config.validate();
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        let config = Arc::new(config);
+        *self.current.write().unwrap() = config.clone();
+        Ok(config)
+    }
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct Database {
+    /// "database/data.db" when missing, ":memory:" keeps the database
+    /// in memory until the server stops
+    pub path: Option<String>,
+    /// "sqlite" when missing, "memory" keeps everything in plain maps
+    /// without SQLite, path is then ignored
+    pub backend: Option<String>
+}
+
+impl Database {
+    pub fn path(&self) -> String {
+        self.path.clone().unwrap_or("database/data.db".to_string())
+    }
+
+    pub fn backend(&self) -> String {
+        self.backend.clone().unwrap_or("sqlite".to_string())
+    }
+}
+
+/// judge settings, the whole section is optional
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct Judge {
+    /// disabled when missing
+    pub compile_cache: Option<CompileCache>,
+    /// number of cases run at the same time over all jobs,
+    /// the number of CPUs when missing
+    pub workers: Option<usize>,
+    /// The unit is byte, 64 MiB when missing and no limit when 0
+    pub output_limit: Option<u64>,
+    /// directory holding the temporary directories of running jobs
+    pub work_root: Option<String>,
+    /// The unit is byte, 64 KiB when missing
+    pub max_source_size: Option<usize>,
+    /// directory holding the case files uploaded with problems
+    pub data_dir: Option<String>,
+    /// The unit is byte, 64 MiB when missing
+    pub max_package_size: Option<usize>,
+    /// The unit is byte, over all files of an imported package, 1 GiB when missing
+    pub max_unpacked_size: Option<u64>,
+    /// files in an imported package, 4096 when missing
+    pub max_package_files: Option<usize>
+}
+
+impl Judge {
+    pub fn workers(&self) -> usize {
+        self.workers.unwrap_or_else(|| {
+            std::thread::available_parallelism().map_or(1, |x| x.get())
+        })
+    }
+
+    pub fn output_limit(&self) -> u64 {
+        self.output_limit.unwrap_or(64 * 1024 * 1024)
+    }
+
+    pub fn work_root(&self) -> String {
+        self.work_root.clone().unwrap_or("tempdir".to_string())
+    }
+
+    pub fn max_source_size(&self) -> usize {
+        self.max_source_size.unwrap_or(64 * 1024)
+    }
+
+    pub fn data_dir(&self) -> String {
+        self.data_dir.clone().unwrap_or("data".to_string())
+    }
+
+    pub fn max_package_size(&self) -> usize {
+        self.max_package_size.unwrap_or(64 * 1024 * 1024)
+    }
+
+    pub fn max_unpacked_size(&self) -> u64 {
+        self.max_unpacked_size.unwrap_or(1024 * 1024 * 1024)
+    }
+
+    pub fn max_package_files(&self) -> usize {
+        self.max_package_files.unwrap_or(4096)
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct CompileCache {
+    /// directory holding the cached artifacts
+    pub path: String,
+    /// The unit is byte, 1 GiB when missing and no limit when 0
+    pub max_size: Option<u64>
+}
+
+impl CompileCache {
+    pub fn max_size(&self) -> u64 {
+        self.max_size.unwrap_or(1024 * 1024 * 1024)
+    }
+}
+
 #[derive(Clone, Deserialize, Serialize)]
 pub struct Problem {
     pub id: usize,
-    name: String,
+    pub name: String,
     #[serde(rename = "type")]
     pub ty: String,
-    misc: Option<Misc>,
+    pub misc: Option<Misc>,
     pub cases: Vec<Case>
 }
 impl Problem {
-    pub fn new() -> Problem {
-        Problem { 
-            id: (0), 
-            name: (String::new()), 
-            ty: (String::new()), 
-            misc: (None), 
-            cases: (Vec::new()) 
+    /// the errors of `validate` joined, for problems from the API or packages
+    pub fn check(&self) -> Result<(), String> {
+        let mut errors = Vec::new();
+        self.validate(&mut errors);
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("; "))
+        }
+    }
+
+    fn validate(&self, errors: &mut Vec<String>) {
+        let prefix = format!("problem {}", self.id);
+        if !["standard", "strict", "spj", "dynamic_ranking"].contains(&self.ty.as_str()) {
+            errors.push(format!("{}: unknown type {:?}", prefix, self.ty));
+        }
+        if self.cases.is_empty() {
+            errors.push(format!("{}: no cases", prefix));
+        }
+        for (index, case) in self.cases.iter().enumerate() {
+            let case_prefix = format!("{} case {}", prefix, index + 1);
+            if !case.score.is_finite() || case.score < 0.0 {
+                errors.push(format!("{}: invalid score {}", case_prefix, case.score));
+            }
+            for path in [&case.input_file, &case.answer_file] {
+                if !Path::new(path).is_file() {
+                    errors.push(format!("{}: file {} not found", case_prefix, path));
+                }
+            }
+        }
+
+        let misc = match &self.misc {
+            Some(misc) => misc,
+            None => {
+                if self.ty == "spj" {
+                    errors.push(format!("{}: spj problem without special_judge", prefix));
+                }
+                return;
+            }
+        };
+        if let Some(packing) = &misc.packing {
+            let mut packed = HashSet::new();
+            for case_id in packing.iter().flatten() {
+                if *case_id < 1 || *case_id > self.cases.len() {
+                    errors.push(format!("{}: packing refers to missing case {}", prefix, case_id));
+                } else if !packed.insert(case_id) {
+                    errors.push(format!("{}: case {} is packed more than once", prefix, case_id));
+                }
+            }
+        }
+        match &misc.special_judge {
+            Some(command) if command.is_empty() => {
+                errors.push(format!("{}: special_judge is empty", prefix));
+            }
+            Some(command) => {
+                // arguments that look like paths should exist
+                for arg in command {
+                    let path_like = arg.starts_with('/') || arg.starts_with('.');
+                    if path_like && !arg.contains('%') && !Path::new(arg).exists() {
+                        errors.push(format!("{}: checker file {} not found", prefix, arg));
+                    }
+                }
+            }
+            None => {
+                if self.ty == "spj" {
+                    errors.push(format!("{}: spj problem without special_judge", prefix));
+                }
+            }
         }
     }
 }
-#[derive(Clone, Deserialize, Serialize)]
-pub struct Misc{
 
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct Misc{
+    /// groups of case ids starting from 1
+    pub packing: Option<Vec<Vec<usize>>>,
+    /// run the cases of a job concurrently
+    pub parallel_cases: Option<bool>,
+    /// skip all cases after the first failed one, as in ICPC
+    pub stop_on_failure: Option<bool>,
+    /// show the first differing line of wrong answers outside contests
+    pub reveal_diff: Option<bool>,
+    /// show the stderr of the program to everyone outside contests
+    pub show_stderr: Option<bool>,
+    /// checker command of spj problems, `%OUTPUT%`, `%ANSWER%` and `%INPUT%`
+    /// are replaced by the paths of the case files
+    pub special_judge: Option<Vec<String>>,
+    /// the checker reports through testlib exit codes and stderr
+    /// instead of printing the result
+    pub testlib_checker: Option<bool>
 }
 
 #[derive(Clone, Deserialize, Serialize)]
@@ -49,14 +354,9 @@ pub struct Case {
     pub time_limit: u64,
     /// The unit is byte, 0 means no limit
     pub memory_limit: u64,
-}
-
-#[derive(Clone, Deserialize, Serialize)]
-enum ProblemType{
-    Standard,
-    Strict,
-    Spj,
-    DynamicRanking
+    /// The unit is byte, 0 means the limit in judge settings
+    #[serde(default)]
+    pub output_limit: u64,
 }
 
 #[derive(Clone, Deserialize, Serialize)]
@@ -67,35 +367,175 @@ pub struct Language {
 }
 
 impl Language {
-    pub fn new() -> Language {
-        Language { 
-            name: (String::new()), 
-            file_name: (String::new()),
-            command: (Vec::new()) 
+    fn validate(&self, errors: &mut Vec<String>) {
+        let prefix = format!("language {}", self.name);
+        if self.file_name.is_empty() {
+            errors.push(format!("{}: file_name is empty", prefix));
+        }
+        if self.command.is_empty() {
+            errors.push(format!("{}: command is empty", prefix));
+            return;
+        }
+        for placeholder in ["%INPUT%", "%OUTPUT%"] {
+            if !self.command.iter().any(|arg| arg.contains(placeholder)) {
+                errors.push(format!("{}: command has no {}", prefix, placeholder));
+            }
         }
     }
 }
 
 /// the Cli struct is for command lines args
-#[derive(Parser)]
+#[derive(Clone, Parser)]
 #[command(name = "oj")]
 #[command(author = "ldt20 <ldt20@mails.tsinghua.edu.cn>")]
 #[command(version = "1.0")]
 #[command(about = "Attention is all you need", long_about = None)]
 pub struct Cli {
-    /// config json
+    /// config file in json, toml or yaml
     #[arg(short, long)]
     pub config: String,
     #[arg(short = 'f', long = "flush-data")]
-    pub flush_data: bool
+    pub flush_data: bool,
+    /// validate the config and exit
+    #[arg(long = "check-config")]
+    pub check_config: bool,
+    /// replaces server.bind_address, may be given more than once
+    #[arg(long = "bind-address")]
+    pub bind_address: Vec<String>,
+    /// replaces server.bind_port
+    #[arg(long = "bind-port")]
+    pub bind_port: Option<u16>,
+    /// replaces server.unix_socket
+    #[arg(long = "unix-socket")]
+    pub unix_socket: Option<String>,
+    /// replaces database.path
+    #[arg(long = "database")]
+    pub database: Option<String>,
+    #[command(subcommand)]
+    pub command: Option<Command>
+}
 
+#[derive(Clone, Subcommand)]
+pub enum Command {
+    /// import a problem package, either a directory or a .zip file
+    Import {
+        path: String
+    },
+    /// apply pending schema migrations
+    Migrate {
+        /// only show the schema version and the pending migrations
+        #[arg(long)]
+        status: bool
+    },
+    /// write users, problems, contests and jobs to a JSON Lines archive
+    Export {
+        path: String
+    },
+    /// load an archive written by export into an empty database
+    Restore {
+        path: String
+    },
+    /// copy the database to a new file, the server may keep running
+    Backup {
+        path: String
+    }
+}
+fn invalid_data(err: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+/// read an OJ_* variable, None when it is not set
+fn env_var<T: FromStr>(name: &str) -> io::Result<Option<T>> {
+    match std::env::var(name) {
+        Ok(value) => value.parse()
+            .map(Some)
+            .map_err(|_| invalid_data(format!("invalid value of {}: {:?}", name, value))),
+        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(err) => Err(invalid_data(format!("invalid value of {}: {}", name, err)))
+    }
+}
+
+impl Config {
+    /// settings from OJ_* environment variables replace those in the file
+    fn apply_env(&mut self) -> io::Result<()> {
+        if let Some(addresses) = env_var::<String>("OJ_BIND_ADDRESS")? {
+            // comma separated
+            let addresses = addresses.split(',').map(|x| x.trim().to_string()).collect();
+            self.server.bind_address = Some(BindAddress::Many(addresses));
+        }
+        if let Some(port) = env_var("OJ_BIND_PORT")? {
+            self.server.bind_port = Some(port);
+        }
+        if let Some(path) = env_var("OJ_UNIX_SOCKET")? {
+            self.server.unix_socket = Some(path);
+        }
+        if let Some(token) = env_var("OJ_ADMIN_TOKEN")? {
+            self.server.admin_token = Some(token);
+        }
+        if let Some(path) = env_var("OJ_DATABASE_PATH")? {
+            self.database.path = Some(path);
+        }
+        if let Some(backend) = env_var("OJ_DATABASE_BACKEND")? {
+            self.database.backend = Some(backend);
+        }
+        if let Some(path) = env_var::<String>("OJ_JUDGE_COMPILE_CACHE")? {
+            let max_size = self.judge.compile_cache.as_ref().and_then(|x| x.max_size);
+            self.judge.compile_cache = Some(CompileCache { path, max_size });
+        }
+        if let Some(workers) = env_var("OJ_JUDGE_WORKERS")? {
+            self.judge.workers = Some(workers);
+        }
+        if let Some(limit) = env_var("OJ_JUDGE_OUTPUT_LIMIT")? {
+            self.judge.output_limit = Some(limit);
+        }
+        if let Some(path) = env_var("OJ_JUDGE_WORK_ROOT")? {
+            self.judge.work_root = Some(path);
+        }
+        if let Some(size) = env_var("OJ_JUDGE_MAX_SOURCE_SIZE")? {
+            self.judge.max_source_size = Some(size);
+        }
+        if let Some(path) = env_var("OJ_JUDGE_DATA_DIR")? {
+            self.judge.data_dir = Some(path);
+        }
+        if let Some(size) = env_var("OJ_JUDGE_MAX_PACKAGE_SIZE")? {
+            self.judge.max_package_size = Some(size);
+        }
+        if let Some(size) = env_var("OJ_JUDGE_MAX_UNPACKED_SIZE")? {
+            self.judge.max_unpacked_size = Some(size);
+        }
+        if let Some(files) = env_var("OJ_JUDGE_MAX_PACKAGE_FILES")? {
+            self.judge.max_package_files = Some(files);
+        }
+        Ok(())
+    }
 }
+
 impl Cli {
     pub fn init_config(&self) -> std::io::Result<Config> {
         let mut file = File::open(&self.config)?;
         let mut contents = String::new();
         file.read_to_string(&mut contents)?;
-        let config: Config = serde_json::from_str(&contents)?;
+        // the format follows the extension, json by default
+        let extension = Path::new(&self.config).extension().and_then(|x| x.to_str());
+        let mut config: Config = match extension {
+            Some("toml") => toml::from_str(&contents).map_err(invalid_data)?,
+            Some("yaml") | Some("yml") => serde_yaml::from_str(&contents).map_err(invalid_data)?,
+            _ => serde_json::from_str(&contents)?
+        };
+        config.apply_env()?;
+        // command line flags win over the config file and the environment
+        if !self.bind_address.is_empty() {
+            config.server.bind_address = Some(BindAddress::Many(self.bind_address.clone()));
+        }
+        if let Some(port) = self.bind_port {
+            config.server.bind_port = Some(port as i32);
+        }
+        if self.unix_socket.is_some() {
+            config.server.unix_socket = self.unix_socket.clone();
+        }
+        if self.database.is_some() {
+            config.database.path = self.database.clone();
+        }
         Ok(config)
     }
 }
\ No newline at end of file
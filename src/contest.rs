@@ -1,22 +1,21 @@
 use actix_web::HttpResponse;
 use serde::{Deserialize, Serialize};
-use r2d2::{Pool};
-use r2d2_sqlite::SqliteConnectionManager;
 use rusqlite::{params, Connection, Result};
-use chrono::NaiveDateTime;
+use chrono::{DateTime, Utc};
 use std::{
     cmp::Ordering,
-    collections::HashSet,
-    str::FromStr
+    collections::HashSet
 };
 
 use crate::{
     get, post,
     web, Responder, 
     ErrorMessage, 
-    user::{User, query_users},
-    job::{query_jobs, JobContent},
-    Config
+    user::User,
+    job::{JobContent, QueryJob},
+    migration,
+    storage::Storage,
+    timestamp
 };
 
 
@@ -26,6 +25,9 @@ struct QueryRanklist {
     tie_breaker: Option<String>
 }
 
+/// the order of users in the ranklist
+type RankOrder<'a> = Box<dyn Fn(&RankContent, &RankContent) -> Ordering + 'a>;
+
 #[derive(Clone, Deserialize, Serialize)]
 struct RankContent {
     user: User,
@@ -34,11 +36,7 @@ struct RankContent {
 }
 impl RankContent {
     fn check_self(&self, user_id: usize) -> bool {
-        if self.user.id == user_id {
-            true
-        }else{
-            false
-        }
+        self.user.id == user_id
     }
 }
 
@@ -46,8 +44,10 @@ impl RankContent {
 struct PostContest {
     id: Option<usize>,
     name: String,
-    from: String,
-    to: String,
+    #[serde(with = "timestamp")]
+    from: DateTime<Utc>,
+    #[serde(with = "timestamp")]
+    to: DateTime<Utc>,
     problem_ids: Vec<usize>,
     user_ids: Vec<usize>,
     submission_limit: u64
@@ -56,10 +56,12 @@ struct PostContest {
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Contest {
-    id: usize,
+    pub id: usize,
     name: String,
-    pub from: String,
-    pub to: String,
+    #[serde(with = "timestamp")]
+    pub from: DateTime<Utc>,
+    #[serde(with = "timestamp")]
+    pub to: DateTime<Utc>,
     pub problem_ids: Vec<usize>,
     pub user_ids: Vec<usize>,
     pub submission_limit: u64
@@ -71,14 +73,12 @@ pub struct Contest {
 #[allow(unreachable_code)]
 async fn post_contests(
     post_contest: web::Json<PostContest>, 
-    config: web::Data<Config>,
-    pool: web::Data<Pool<SqliteConnectionManager>>
+    storage: web::Data<dyn Storage>
 ) -> impl Responder {
     log::info!("Post contests {}", post_contest.name);
-    let conn = pool.get().unwrap();
     // check problem ids
     for problem_id in &post_contest.problem_ids {
-        if problem_id >= &config.problems.len() {        
+        if storage.problem(*problem_id).is_err() {
             return HttpResponse::NotFound().json({
                 ErrorMessage{
                     code: (3),
@@ -90,9 +90,8 @@ async fn post_contests(
     }
 
     // check user ids
-    let user_list = query_users(&conn).unwrap();
     for user_id in &post_contest.user_ids {
-        if user_id >= &user_list.len() {
+        if storage.user(*user_id).is_err() {
             return HttpResponse::NotFound().json({
                 ErrorMessage{
                     code: (3),
@@ -102,8 +101,7 @@ async fn post_contests(
             });
         }
     }
-    
-    let contest_list = query_contests(&conn).unwrap();
+
     match post_contest.id {
         Some( id ) => {
             // check contest id
@@ -117,18 +115,18 @@ async fn post_contests(
                 })
             }
             else {
-                match query_contest(&conn, id) {
+                match storage.contest(id) {
                     Ok(_) => {
                         let contest = Contest{
                             id: (id),
                             name: (post_contest.name.clone()),
-                            from: (post_contest.from.clone()),
-                            to: (post_contest.to.clone()),
+                            from: (post_contest.from),
+                            to: (post_contest.to),
                             problem_ids: (post_contest.problem_ids.clone()),
                             user_ids: (post_contest.user_ids.clone()),
-                            submission_limit: (post_contest.submission_limit.clone())
+                            submission_limit: (post_contest.submission_limit)
                         };
-                        update_contest(&conn, &contest).unwrap();
+                        storage.update_contest(&contest).unwrap();
                         HttpResponse::Ok().json(contest)
                     }
                     Err(_) => {
@@ -144,16 +142,17 @@ async fn post_contests(
             }
         }
         None => {
-            let contest = Contest{
-                id: (contest_list.len() + 1),
+            // the id is allocated when the contest is inserted
+            let mut contest = Contest{
+                id: (0),
                 name: (post_contest.name.clone()),
-                from: (post_contest.from.clone()),
-                to: (post_contest.to.clone()),
+                from: (post_contest.from),
+                to: (post_contest.to),
                 problem_ids: (post_contest.problem_ids.clone()),
                 user_ids: (post_contest.user_ids.clone()),
-                submission_limit: (post_contest.submission_limit.clone())
+                submission_limit: (post_contest.submission_limit)
             };
-            insert_contest(&conn, &contest).unwrap();
+            storage.insert_contest(&mut contest).unwrap();
             HttpResponse::Ok().json(contest)
         }
     }
@@ -164,11 +163,10 @@ async fn post_contests(
 #[get("contests")]
 #[allow(unreachable_code)]
 async fn get_contests(
-    pool: web::Data<Pool<SqliteConnectionManager>>
+    storage: web::Data<dyn Storage>
 ) -> impl Responder {
     log::info!("Get contests");
-    let conn = pool.get().unwrap();
-    let contest_list = query_contests(&conn).unwrap();
+    let contest_list = storage.contests().unwrap();
     HttpResponse::Ok().json(contest_list)
 }
 
@@ -176,11 +174,10 @@ async fn get_contests(
 #[allow(unreachable_code)]
 async fn get_contests_id(
     contest_id: web::Path<usize>,
-    pool: web::Data<Pool<SqliteConnectionManager>>
+    storage: web::Data<dyn Storage>
 ) -> impl Responder {
     log::info!("Get contests {contest_id}");
-    let conn = pool.get().unwrap();
-    let query_contest = query_contest(&conn, *contest_id);
+    let query_contest = storage.contest(*contest_id);
     match query_contest {
         Ok(contest) => HttpResponse::Ok().json(contest),
         Err(_) => HttpResponse::NotFound().json({
@@ -199,21 +196,26 @@ async fn get_contests_id(
 async fn get_contests_id_ranklist(
     contest_id: web::Path<usize>, 
     request: web::Query<QueryRanklist>, 
-    config: web::Data<Config>,
-    pool: web::Data<Pool<SqliteConnectionManager>>
+    storage: web::Data<dyn Storage>
 ) -> impl Responder {
     log::info!("Get contests {} ranklist", contest_id);
-    let conn = pool.get().unwrap();
-    let contest_list = query_contests(&conn).unwrap();
-    if *contest_id <= contest_list.len() {
+    // contest 0 has all users and problems
+    let contest = match *contest_id {
+        0 => Ok(None),
+        contest_id => storage.contest(contest_id).map(Some)
+    };
+    if let Ok(contest) = contest {
         
         let mut rank_contents = Vec::new();
-        let user_list = query_users(&conn).unwrap();
-        let mut user_ids: Vec<usize> = (0..user_list.len()).collect();
-        let mut problem_ids: Vec<usize> = (0..config.problems.len()).collect();
-        if  *contest_id > 0 {
-            user_ids = contest_list[*contest_id - 1].user_ids.clone();
-            problem_ids = contest_list[*contest_id - 1].problem_ids.clone();
+        let user_list = storage.users().unwrap();
+        let mut user_ids: Vec<usize> = user_list.iter().map(|x| x.id).collect();
+        let mut problem_ids: Vec<usize> = storage.problems().unwrap()
+            .iter()
+            .map(|x| x.id)
+            .collect();
+        if let Some(contest) = &contest {
+            user_ids = contest.user_ids.clone();
+            problem_ids = contest.problem_ids.clone();
         }
 
         // init rank
@@ -229,14 +231,18 @@ async fn get_contests_id_ranklist(
         }
         
         
-        let job_list_raw = query_jobs(&conn).unwrap();
+        let job_list_raw = storage.jobs(&QueryJob {
+            contest_id: contest.as_ref().map(|x| x.id),
+            ..QueryJob::default()
+        }).unwrap();
         let mut job_list =Vec::new();
-        if *contest_id == 0 {
-            job_list = job_list_raw.clone();
-        }else {
-            for job in &*job_list_raw {
-                if check_job(job, &contest_list[*contest_id - 1]) {
-                    job_list.push(job.clone());
+        match &contest {
+            None => job_list = job_list_raw.clone(),
+            Some(contest) => {
+                for job in &*job_list_raw {
+                    if check_job(job, contest) {
+                        job_list.push(job.clone());
+                    }
                 }
             }
         }
@@ -250,19 +256,12 @@ async fn get_contests_id_ranklist(
             let user_id = job.submission.user_id;
             let problem_id = job.submission.problem_id;
 
-            let mut rank_content_id = 0;
-            for index in 0..rank_contents.len() {
-                if rank_contents[index].check_self(user_id) {
-                    rank_content_id = index;
-                    break;
-                }
-            }
-            let mut score_id: usize = 0;
-            for index in 0..problem_ids.len() {
-                if problem_id == problem_ids[index] {
-                    score_id = index;
-                }
-            }
+            let rank_content_id = rank_contents.iter()
+                .position(|x| x.check_self(user_id))
+                .unwrap_or(0);
+            let score_id = problem_ids.iter()
+                .rposition(|x| *x == problem_id)
+                .unwrap_or(0);
 
             let mut is_update = false;
             match &request.scoring_rule {
@@ -291,7 +290,7 @@ async fn get_contests_id_ranklist(
         }
 
         // sort
-        let mut compare: Box<dyn Fn(&RankContent, &RankContent) -> Ordering> = Box::new(|a, b| {
+        let mut compare: RankOrder = Box::new(|a, b| {
             b.scores.iter().sum::<f64>()
             .partial_cmp(&a.scores.iter().sum::<f64>())
             .unwrap_or(Ordering::Equal)
@@ -337,7 +336,10 @@ async fn get_contests_id_ranklist(
         rank_contents.sort_by(&compare);
         // assign rank
         let mut rank = 1;
-        rank_contents[0].rank = rank;
+        // a contest may have no users
+        if let Some(first) = rank_contents.first_mut() {
+            first.rank = rank;
+        }
         for index in 1..rank_contents.len() {
             rank += 1;
             if compare(&rank_contents[index], &rank_contents[index - 1]) == Ordering::Equal {
@@ -375,17 +377,10 @@ pub fn check_job(job: &JobContent, contest: &Contest) -> bool {
     if !contest.problem_ids.contains(&problem_id) {
         return false;
     }
-    let fmt = "%Y-%m-%dT%H:%M:%S%.3fZ";
-    let from_time = NaiveDateTime::parse_from_str(&contest.from, fmt)
-    .expect("failed to get time");
-    let to_time = NaiveDateTime::parse_from_str(&contest.to, fmt)
-    .expect("failed to get time");
-    let creat_time = NaiveDateTime::parse_from_str(&job.created_time, fmt)
-    .expect("failed to get time");
-    if to_time < creat_time {
+    if contest.to < job.created_time {
         return false;
     }
-    if from_time > creat_time {
+    if contest.from > job.created_time {
         return false;
     }
     if job.state != "Finished" {
@@ -396,92 +391,117 @@ pub fn check_job(job: &JobContent, contest: &Contest) -> bool {
 }
 
 
-pub fn format_vec(v: &Vec<usize>) -> String {
-    v.iter()
-        .map(|x| x.to_string())
-        .collect::<Vec<String>>()
-        .join(",")
+/// problem or user ids of a contest, in the order they were given
+fn query_members(conn: &Connection, table: &str, column: &str, contest_id: usize) -> Result<Vec<usize>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM {} WHERE contest_id = ? ORDER BY position",
+        column, table
+    ))?;
+    let ids = stmt.query_map(params![contest_id], |row| row.get(0))?;
+    ids.collect()
 }
 
-pub fn parse_vec(s: String) -> Vec<usize> {
-    s.split(',')
-        .map(|x| usize::from_str(x).unwrap())
-        .collect()
+fn save_members(conn: &Connection, table: &str, column: &str, contest_id: usize, ids: &[usize]) -> Result<()> {
+    conn.execute(&format!("DELETE FROM {} WHERE contest_id = ?", table), params![contest_id])?;
+    let mut stmt = conn.prepare(&format!(
+        "INSERT INTO {} (contest_id, position, {}) VALUES (?, ?, ?)",
+        table, column
+    ))?;
+    for (position, id) in ids.iter().enumerate() {
+        stmt.execute(params![contest_id, position, id])?;
+    }
+    Ok(())
 }
 
-fn insert_contest(conn: &Connection, contest: &Contest) -> Result<()> {
+/// let the database allocate the id of the contest and save it, ids start from 1
+pub fn insert_contest(conn: &Connection, contest: &mut Contest) -> Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    contest.id = write_contest(&tx, None, contest)?;
+    tx.commit()
+}
+
+/// save a contest and its members with the id as is
+pub fn save_contest(conn: &Connection, contest: &Contest) -> Result<()> {
+    write_contest(conn, Some(contest.id), contest)?;
+    Ok(())
+}
+
+/// a missing id is allocated, return the id
+fn write_contest(conn: &Connection, id: Option<usize>, contest: &Contest) -> Result<usize> {
     conn.execute(
-        "INSERT INTO contests 
-        (id, name, 'from', 'to', problem_ids, user_ids, submission_limit) 
-        VALUES (?, ?, ?, ?, ?, ?, ?)",
+        &format!("INSERT INTO contests 
+        (id, name, 'from', 'to', submission_limit) 
+        VALUES (COALESCE(?, {}), ?, ?, ?, ?)", migration::next_id_sql("contests")),
         params![
-            contest.id, 
+            id, 
             contest.name,
-            contest.from,
-            contest.to,
-            format_vec(&contest.problem_ids),
-            format_vec(&contest.user_ids),
+            timestamp::format(&contest.from),
+            timestamp::format(&contest.to),
             contest.submission_limit
         ],
     )?;
-    Ok(())
+    let id = conn.last_insert_rowid() as usize;
+    save_members(conn, "contest_problems", "problem_id", id, &contest.problem_ids)?;
+    save_members(conn, "contest_users", "user_id", id, &contest.user_ids)?;
+    Ok(id)
 }
 
-fn update_contest(conn: &Connection, contest: &Contest) -> Result<()> {
-    conn.execute(
+pub fn update_contest(conn: &Connection, contest: &Contest) -> Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    tx.execute(
         "UPDATE contests SET 
                 name = ?, 
                 'from' = ?, 
                 'to' = ?, 
-                problem_ids = ?, 
-                user_ids = ?, 
                 submission_limit = ? 
                 WHERE id = ?",
         params![ 
             contest.name,
-            contest.from,
-            contest.to,
-            format_vec(&contest.problem_ids),
-            format_vec(&contest.user_ids),
+            timestamp::format(&contest.from),
+            timestamp::format(&contest.to),
             contest.submission_limit,
             contest.id
         ],
     )?;
+    save_members(&tx, "contest_problems", "problem_id", contest.id, &contest.problem_ids)?;
+    save_members(&tx, "contest_users", "user_id", contest.id, &contest.user_ids)?;
+    tx.commit()
+}
+
+fn read_contest(row: &rusqlite::Row) -> Result<Contest> {
+    Ok(Contest {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        from: timestamp::read(row, 2)?,
+        to: timestamp::read(row, 3)?,
+        problem_ids: Vec::new(),
+        user_ids: Vec::new(),
+        submission_limit: row.get(4)?
+    })
+}
+
+/// fill in the problems and users of a contest
+fn query_contest_members(conn: &Connection, contest: &mut Contest) -> Result<()> {
+    contest.problem_ids = query_members(conn, "contest_problems", "problem_id", contest.id)?;
+    contest.user_ids = query_members(conn, "contest_users", "user_id", contest.id)?;
     Ok(())
 }
 
 pub fn query_contests(conn: &Connection) -> Result<Vec<Contest>> {
-    let mut stmt = conn.prepare("SELECT * FROM contests")?;
-
-    let rows = stmt.query_map([], |row| {
-        Ok(Contest {
-            id: row.get(0)?,
-            name: row.get(1)?,
-            from: row.get(2)?,
-            to: row.get(3)?,
-            problem_ids: parse_vec(row.get(4)?),
-            user_ids: parse_vec(row.get(5)?),
-            submission_limit: row.get(6)?
-        })
-    })?;
-
-    let contests = rows.collect();
-    contests
+    let mut stmt = conn.prepare("SELECT id, name, \"from\", \"to\", submission_limit FROM contests")?;
+    let rows = stmt.query_map([], read_contest)?;
+    let mut contests: Vec<Contest> = rows.collect::<Result<_>>()?;
+    for contest in &mut contests {
+        query_contest_members(conn, contest)?;
+    }
+    Ok(contests)
 }
 
 
 pub fn query_contest(conn: &Connection, id: usize) -> Result<Contest> {
-    let mut stmt = conn.prepare("SELECT * FROM contests WHERE id = ?")?;
-    let mut contests = stmt.query_map(params![id], |row| {
-        Ok(Contest {
-            id: row.get(0)?,
-            name: row.get(1)?,
-            from: row.get(2)?,
-            to: row.get(3)?,
-            problem_ids: parse_vec(row.get(4)?),
-            user_ids: parse_vec(row.get(5)?),
-            submission_limit: row.get(6)?
-        })
-    })?;
-    contests.next().unwrap_or(Err(rusqlite::Error::QueryReturnedNoRows))
-}
\ No newline at end of file
+    let mut stmt = conn.prepare("SELECT id, name, \"from\", \"to\", submission_limit FROM contests WHERE id = ?")?;
+    let mut contests = stmt.query_map(params![id], read_contest)?;
+    let mut contest = contests.next().unwrap_or(Err(rusqlite::Error::QueryReturnedNoRows))?;
+    query_contest_members(conn, &mut contest)?;
+    Ok(contest)
+}
@@ -1,77 +1,147 @@
-use actix_web::HttpResponse;
+use actix_web::{HttpRequest, HttpResponse};
 use chrono::*;
 use serde::{Deserialize, Serialize};
-use r2d2::{Pool, PooledConnection};
-use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::{params, Connection, Result};
+use rusqlite::{params, params_from_iter, Connection, Result, Row, Transaction, TransactionBehavior, types::{Value, ValueRef}};
+use base64::{Engine, engine::general_purpose};
 use std::{
     process::{Stdio}, 
-    fs::{self, File},
-    str::FromStr
+    fs::{self, File, TryLockError},
+    io::{BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+    cell::RefCell,
+    sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}},
+    collections::HashMap
 };
 
 use tokio::process::Command;
+use tokio::io::AsyncReadExt;
+use tokio::sync::Semaphore;
 
 use tokio::time::{timeout, Duration};
 
 use crate::{
     post, get, put, delete,
     web, Responder, 
-    Config, config::{Language, self, Problem},
+    SharedConfig, config::{Language, self, Problem},
     ErrorMessage,
-    user::{query_users},
-    contest::{check_job, query_contests}
+    contest::check_job,
+    storage::{self, Storage, StorageError},
+    cache::CompileCache,
+    migration,
+    timestamp
 };
 
 // id time memory should be u64
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct PostJob {
     source_code: String,
-    language: String,
+    /// "base64" when `source_code` is base64 encoded, plain text when missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source_encoding: Option<String>,
+    pub language: String,
     pub user_id: usize,
     pub contest_id: usize,
     pub problem_id: usize,
 }
 impl PostJob {
     fn new() -> PostJob{
-        PostJob { source_code: (String::new()), language: (String::new()), user_id: (0), contest_id: (0), problem_id: (0) }
+        PostJob { source_code: (String::new()), source_encoding: (None), language: (String::new()), user_id: (0), contest_id: (0), problem_id: (0) }
     }
-}
 
-#[derive(Clone, Deserialize, Serialize)]
-struct Response {
-    status: i32,
-    content: JobContent,
+    /// the exact bytes of the submitted file, None if they cannot be decoded
+    fn source_bytes(&self) -> Option<Vec<u8>> {
+        match self.source_encoding.as_deref() {
+            None => Some(self.source_code.clone().into_bytes()),
+            Some("base64") => general_purpose::STANDARD.decode(&self.source_code).ok(),
+            Some(_) => None
+        }
+    }
+
+    /// plain text is stored as TEXT, anything else as BLOB
+    fn source_value(&self) -> Value {
+        match self.source_encoding {
+            None => Value::Text(self.source_code.clone()),
+            Some(_) => Value::Blob(self.source_bytes().unwrap_or_default())
+        }
+    }
 }
 
 
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct JobContent {
-    id: usize,
-    pub created_time: String,
-    updated_time: String,
+    pub id: usize,
+    #[serde(with = "timestamp")]
+    pub created_time: DateTime<Utc>,
+    #[serde(with = "timestamp")]
+    pub updated_time: DateTime<Utc>,
     pub submission: PostJob,
     pub state: String,
-    result: String,
+    pub result: String,
     pub score: f64,
     cases: Vec<Case>,
+    /// bumped by every state transition
+    #[serde(skip)]
+    pub version: u64
 }
 
 impl JobContent {
     fn new(len: usize) -> JobContent{
         JobContent {
             id: (len), 
-            created_time: (String::new()),
-            updated_time: (String::new()),
+            created_time: (timestamp::now()),
+            updated_time: (timestamp::now()),
             submission: (PostJob::new()),
             state: ("Queueing".to_string()), 
             result: ("Waiting".to_string()), 
             score: (0.0), 
-            cases: (Vec::new()) 
+            cases: (Vec::new()),
+            version: (0)
         }
     }
 
+    /// stderr is shown to admins, and to everyone for practice jobs
+    /// of problems with `show_stderr`
+    pub fn hide_stderr(&mut self, admin: bool, problem: Option<&Problem>) {
+        if admin {
+            return;
+        }
+        if self.submission.contest_id == 0 {
+            let show_stderr = problem
+                .and_then(|x| x.misc.as_ref())
+                .and_then(|x| x.show_stderr)
+                .unwrap_or(false);
+            if show_stderr {
+                return;
+            }
+        }
+        for case in &mut self.cases {
+            case.stderr = None;
+        }
+    }
+
+    /// take the given cases of `job`, all of them when None,
+    /// the way `transition_job` stores them
+    pub fn copy_cases(&mut self, job: &JobContent, case_ids: Option<&[usize]>) {
+        let case_ids = match case_ids {
+            Some(case_ids) => case_ids,
+            None => {
+                self.cases = job.cases.clone();
+                return;
+            }
+        };
+        for case_id in case_ids {
+            let case = job.cases[*case_id].clone();
+            match self.cases.iter_mut().find(|x| x.id == case.id) {
+                Some(stored) => *stored = case,
+                None => {
+                    self.cases.push(case);
+                    self.cases.sort_by_key(|x| x.id);
+                }
+            }
+        }
+    }
 }
 
 
@@ -81,7 +151,10 @@ struct Case {
     result: String,
     time: u64,
     memory: u64,
-    info: String
+    info: String,
+    /// truncated stderr of the program, hidden from most users
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stderr: Option<String>
 }
 impl Case {
     fn new(case_id: usize) -> Case {
@@ -90,55 +163,108 @@ impl Case {
             result: ("Waiting".to_string()), 
             time: (0), 
             memory: (0), 
-            info: (String::new()) 
+            info: (String::new()),
+            stderr: (None)
         }
     }
 }
 
+// distinguish the runs of one job, e.g. concurrent rejudges
+static RUN_COUNT: AtomicUsize = AtomicUsize::new(0);
 
-/// Temporary directories
+/// prefix of every run directory under the work root,
+/// followed by the pid of the server owning it
+const RUN_DIR_PREFIX: &str = "run-";
+
+// lock files held while the server runs, one per work root
+static WORK_ROOT_LOCKS: Mutex<Vec<(String, File)>> = Mutex::new(Vec::new());
+
+/// the lock file of a server in a work root
+fn lock_path(work_root: &str, pid: u32) -> PathBuf {
+    Path::new(work_root).join(format!("{}{}.lock", RUN_DIR_PREFIX, pid))
+}
+
+/// hold the lock file of this server in the work root, so that other
+/// servers sharing it leave its run directories alone
+fn claim_work_root(work_root: &str) -> std::io::Result<()> {
+    let mut locks = WORK_ROOT_LOCKS.lock().unwrap();
+    if locks.iter().any(|(root, _)| root == work_root) {
+        return Ok(());
+    }
+    fs::create_dir_all(work_root)?;
+    let file = File::create(lock_path(work_root, std::process::id()))?;
+    file.lock()?;
+    locks.push((work_root.to_string(), file));
+    Ok(())
+}
+
+/// whether the server with this pid still holds its lock file
+fn owner_running(work_root: &str, pid: u32) -> bool {
+    match File::open(lock_path(work_root, pid)) {
+        Ok(file) => matches!(file.try_lock(), Err(TryLockError::WouldBlock)),
+        Err(_) => false
+    }
+}
+
+/// Temporary directories, removed when dropped
 struct Tempdir {
     path: String,
     src_path: String,
     app_path: String,
 }
 
+impl Drop for Tempdir {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_dir_all(&self.path) {
+            log::warn!("Failed to remove {}: {}", self.path, err);
+        }
+    }
+}
 
 impl Tempdir {
-    /// init temporary directory
-    async fn init_tempdir(&self){
-         // init temporary directory
-         let _mkdir_status = Command::new("mkdir")
-         .arg(&self.path)
-         .status()
-         .await
-         .expect("failed to execute process");
-    }
-    /// delete temporary directory
-    async fn delete_tempdir(&self){
-        let _mkdir_status = Command::new("rm")
-        .args(["-rf", &self.path])
-        .status()
-        .await
-        .expect("failed to execute process");
+    /// create a unique temporary directory for one run of a job
+    fn new(work_root: &str, job_id: usize) -> std::io::Result<Tempdir> {
+        // the work root may have changed with a reload
+        claim_work_root(work_root)?;
+        let name = format!(
+            "{}{}-{}-{}",
+            RUN_DIR_PREFIX,
+            std::process::id(),
+            job_id,
+            RUN_COUNT.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = Path::new(work_root).join(name);
+        fs::create_dir(&path)?;
+        let path = path.to_string_lossy().to_string() + "/";
+        Ok(Tempdir {
+            app_path: (path.clone() + "test"),
+            src_path: (String::new()),
+            path: (path)
+        })
     }
 
     /// compilate source code
-    async fn compilate(&mut self, language: &Language, source_code: &String) -> bool {
+    async fn compilate(
+        &mut self,
+        language: &Language,
+        source_code: &[u8],
+        cache: &Option<CompileCache>
+    ) -> bool {
 
         // write source code to file
         self.src_path = self.path.clone() + &language.file_name;
-        let src_file = File::create(self.src_path.clone())
-        .expect("failed to creat file");
+        fs::write(&self.src_path, source_code).expect("failed to write file");
 
-        let _write_status = Command::new("echo")
-        .arg(source_code.to_string())
-        .stdout(Stdio::from(src_file))
-        .status()
-        .await;
+        // reuse the binary of an identical submission
+        self.app_path = self.path.clone() + "test";
+        let key = CompileCache::key(language, source_code);
+        if let Some(cache) = cache {
+            if cache.fetch(&key, &self.app_path) {
+                return true;
+            }
+        }
 
         // compilate
-        self.app_path = self.path.clone() + "test";
         let mut args = language.command[1..].to_vec();
         args.iter_mut().for_each(|x| {if *x == "%OUTPUT%" {*x = self.app_path.clone()}});
         args.iter_mut().for_each(|x| {if *x == "%INPUT%" {*x = self.src_path.clone()}});
@@ -147,24 +273,60 @@ impl Tempdir {
         .args(args)
         .status()
         .await;
-        compilate_status.unwrap().success()
-
-        
-                            
+        let success = compilate_status.unwrap().success();
+        if success {
+            if let Some(cache) = cache {
+                cache.store(&key, &self.app_path);
+            }
+        }
+        success
     }
 
-    /// judge case
-    async fn judge(&self, case: &config::Case, ty: &String) -> String {
-
-
+    /// judge case, a run exiting with a non-zero status is a Runtime Error
+    /// before its output is looked at, even if the output is right
+    async fn judge(
+        &self,
+        case_id: usize,
+        case: &config::Case,
+        ty: &str,
+        checker: &Option<Checker>,
+        output_limit: u64,
+        reveal_diff: bool
+    ) -> Case {
+
+        // every case has its own output file so that cases can run concurrently
+        let out_path = self.path.clone() + &case_id.to_string() + ".out";
         let in_file = File::open(case.input_file.clone()).expect("failed to open file");
-        let out_file = File::create(self.path.clone() + "test.out").expect("failed to creat file");
+        let mut out_file = File::create(out_path.clone()).expect("failed to creat file");
+        let err_path = self.path.clone() + &case_id.to_string() + ".err";
+        let err_file = File::create(err_path.clone()).expect("failed to creat file");
 
-        let run_status = Command::new(self.app_path.clone())
+        let mut child = Command::new(self.app_path.clone())
         .stdin(Stdio::from(in_file))
-        .stdout(Stdio::from(out_file))
-        .status();
-
+        .stdout(Stdio::piped())
+        .stderr(Stdio::from(err_file))
+        .kill_on_drop(true)
+        .spawn()
+        .expect("failed to execute process");
+        let mut stdout = child.stdout.take().expect("failed to get stdout");
+
+        // copy the output while it is produced, stop once it is too large
+        let run_status = async {
+            let mut buffer = [0u8; 8192];
+            let mut output_size: u64 = 0;
+            loop {
+                let size = stdout.read(&mut buffer).await?;
+                if size == 0 {
+                    break;
+                }
+                output_size += size as u64;
+                if output_limit > 0 && output_size > output_limit {
+                    return Ok(None);
+                }
+                out_file.write_all(&buffer[..size])?;
+            }
+            child.wait().await.map(Some)
+        };
 
         let mut wait_timeout = Duration::MAX; 
         if case.time_limit > 0 {
@@ -173,184 +335,569 @@ impl Tempdir {
         let time_status = timeout(wait_timeout, run_status)
         .await;
 
-        match time_status {
+        let mut result = Case::new(case_id);
+        result.result = match time_status {
             Ok(status) => {
                 match status {
-                    Ok(_) => {
-                        if self.compare_out_ans(&case.answer_file, ty) {
-                            "Accepted".to_string()
-                        }else {
+                    Ok(Some(status)) if !status.success() => "Runtime Error".to_string(),
+                    Ok(Some(_)) if checker.is_some() => {
+                        let checker = checker.as_ref().unwrap();
+                        let (checker_result, info) = self.special_judge(checker, case, &out_path).await;
+                        result.info = info;
+                        checker_result
+                    }
+                    Ok(Some(_)) => match self.compare_out_ans(&out_path, &case.answer_file, ty) {
+                        Some((true, _)) => "Accepted".to_string(),
+                        Some((false, diff)) => {
+                            if reveal_diff {
+                                result.info = diff;
+                            }
                             "Wrong Answer".to_string()
                         }
-                    }
+                        // the answer file is missing or unreadable
+                        None => "System Error".to_string()
+                    },
+                    Ok(None) => "Output Limit Exceeded".to_string(),
                     Err(_) => "Runtime Error".to_string()
                 }
             }
             Err(_) => {
                 "Time Limit Exceeded".to_string()
             }
+        };
+        result.stderr = read_stderr(&err_path);
+        result
+    }
+
+    /// run the checker, it prints the result on the first line
+    /// and the info on the second line
+    async fn special_judge(&self, checker: &Checker, case: &config::Case, out_path: &str) -> (String, String) {
+        let args: Vec<String> = checker.command.iter()
+            .map(|arg| {
+                arg.replace("%OUTPUT%", out_path)
+                    .replace("%ANSWER%", &case.answer_file)
+                    .replace("%INPUT%", &case.input_file)
+            })
+            .collect();
+        if args.is_empty() {
+            return ("SPJ Error".to_string(), String::new());
+        }
+        let output = Command::new(&args[0])
+            .args(&args[1..])
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .output()
+            .await;
+        let output = match output {
+            Ok(output) => output,
+            Err(_) => return ("SPJ Error".to_string(), String::new())
+        };
+        if checker.testlib {
+            // 0 is ok, 1 is wrong answer and 2 is presentation error
+            let result = match output.status.code() {
+                Some(0) => "Accepted",
+                Some(1) | Some(2) => "Wrong Answer",
+                _ => return ("SPJ Error".to_string(), String::new())
+            };
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let info = stderr.lines().next().unwrap_or("").trim().to_string();
+            return (result.to_string(), info);
+        }
+        if !output.status.success() {
+            return ("SPJ Error".to_string(), String::new());
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let mut lines = stdout.lines();
+        match lines.next().map(|x| x.trim()) {
+            Some(result) if result == "Accepted" || result == "Wrong Answer" => {
+                let info = lines.next().unwrap_or("").trim().to_string();
+                (result.to_string(), info)
+            }
+            _ => ("SPJ Error".to_string(), String::new())
         }
     }
 
-    /// compare output file and answer file
-    fn compare_out_ans(&self, ans_path: &String, ty: &String) -> bool{
-        let output_content = fs::read_to_string(self.path.clone() + "test.out")
-        .expect("failed to read file");
-        let answer_content = fs::read_to_string(ans_path.clone())
-        .expect("failed to read file");
-        match ty.as_str() {
-            "standard" => {
-                
-                // the same lines
-                let mut lines_count = 0;
-                for (line1, line2) in output_content.lines().zip(answer_content.lines()){
-                    let trimmed1 = line1.trim_end();
-                    let trimmed2 = line2.trim_end();
-                    lines_count += 2;
-                    if trimmed1 != trimmed2 {
-                        println!("same lines wrong");
-                        return false;
-                    }
-                } 
-
-                // more lines
-                for line in output_content.lines().chain(answer_content.lines()).skip(lines_count){
-                    let trimmed = line.trim_end();
-                    if trimmed.len() > 0 {
-                        println!("line_count {} more lines wrong:{:?}",lines_count,trimmed);
-                        return false;
-                    }
-                }
-                for line in answer_content.lines().chain(output_content.lines()).skip(lines_count){
-                    let trimmed = line.trim_end();
-                    if trimmed.len() > 0 {
-                        println!("line_count {} more lines wrong:{:?}",lines_count,trimmed);
-                        return false;
+    /// compare output file and answer file,
+    /// return whether they match and where they first differ,
+    /// None if either file cannot be opened
+    fn compare_out_ans(&self, out_path: &str, ans_path: &str, ty: &str) -> Option<(bool, String)> {
+        let output = BufReader::new(File::open(out_path).ok()?);
+        let answer = BufReader::new(File::open(ans_path).ok()?);
+        match ty {
+            "standard" => Some(compare(output, answer, false)),
+            "strict" => Some(compare(output, answer, true)),
+            _ => Some((false, String::new()))
+        }
+    }
+
+}
+
+/// compare the output with the answer byte by byte,
+/// return whether they match and where they first differ
+fn compare(output: impl BufRead, answer: impl BufRead, strict: bool) -> (bool, String) {
+    let mut output = Compared::new(output);
+    let mut answer = Compared::new(answer);
+    let mut line_number = 1;
+    loop {
+        let output_byte = output.peek();
+        if output_byte == answer.peek() {
+            match output_byte {
+                None => return (true, String::new()),
+                Some(byte) => {
+                    output.advance();
+                    answer.advance();
+                    if byte == b'\n' {
+                        line_number += 1;
                     }
                 }
-                true
             }
-            "strict" => {
-                output_content == answer_content
+            continue;
+        }
+        // trailing spaces and trailing empty lines are ignored
+        if !strict && output.skip_trailing_spaces() && answer.skip_trailing_spaces() {
+            // both are at a line break or at the end of file,
+            // the one at a line break moves on to its next line
+            if output.peek() != answer.peek() {
+                output.advance();
+                answer.advance();
+                line_number += 1;
             }
-            _ => false
+            continue;
+        }
+        let mut output_line = output.line();
+        let mut answer_line = answer.line();
+        if !strict {
+            output_line = output_line.map(|x| x.trim_ascii_end().to_vec());
+            answer_line = answer_line.map(|x| x.trim_ascii_end().to_vec());
         }
+        let diff = format!(
+            "line {}: expected {}, received {}",
+            line_number,
+            excerpt(&answer_line),
+            excerpt(&output_line)
+        );
+        return (false, diff);
     }
+}
 
+/// a file compared byte by byte, only the start of the current line
+/// is kept for the diff so that long lines take no memory
+struct Compared<R> {
+    reader: R,
+    line: Vec<u8>
 }
 
-async fn judge_job(
-    job_id: usize,
-    pool: web::Data<Pool<SqliteConnectionManager>>,
-    problems: Vec<Problem>,
-    languages: Vec<Language>
-) {
-    let conn = pool.get().unwrap();
-    match query_job(&conn, job_id) {
-        Ok(mut job) => {
-            //init temporary directory
-            let mut tempdir = Tempdir {
-                path: ("tempdir".to_string()+ &job_id.to_string() + "/"),
-                src_path: (String::new()),
-                app_path: ("tempdir".to_string()+ &job_id.to_string() + "/test")
-            };
-            tempdir.init_tempdir().await;
-            
-            let problem = &problems[job.submission.problem_id];
-            let mut language = &Language::new();
-            for index in 0..languages.len() {
-                language = &languages[index];
-                if job.submission.language == language.name {
-                    break;
-                }
-            }
+impl<R: BufRead> Compared<R> {
+    fn new(reader: R) -> Self {
+        Compared { reader, line: Vec::new() }
+    }
+
+    /// the next byte, None at the end of file
+    fn peek(&mut self) -> Option<u8> {
+        self.reader.fill_buf().ok()?.first().copied()
+    }
 
-            let cases = problem.cases.clone();
-            // init
-            job.state = "Running".to_string();
-            
-            update_job(&conn, &job).unwrap();
-            // init cases state
-            let old_len = job.cases.len();
-            for _cases_count in 1..old_len {
-                job.cases.pop();
+    fn advance(&mut self) {
+        if let Some(byte) = self.peek() {
+            self.reader.consume(1);
+            if byte == b'\n' {
+                self.line.clear();
+            } else if self.line.len() < DIFF_EXCERPT_BYTES {
+                self.line.push(byte);
             }
-            for case_id in 1..=cases.len() {
-                job.cases.push(Case::new(case_id));
+        }
+    }
+
+    /// skip the spaces before the next line break,
+    /// false if anything else comes before it
+    fn skip_trailing_spaces(&mut self) -> bool {
+        loop {
+            match self.peek() {
+                None | Some(b'\n') => return true,
+                Some(byte) if byte.is_ascii_whitespace() => self.advance(),
+                Some(_) => return false
             }
-            // compilate
+        }
+    }
 
-            if tempdir.compilate(language, &job.submission.source_code).await{
-                job.cases[0].result = "Compilation Success".to_string();
-            }else {
-                job.cases[0].result = "Compilation Error".to_string();
-                job.result = "Compilation Error".to_string();
+    /// the start of the current line, None at the end of file
+    fn line(&mut self) -> Option<Vec<u8>> {
+        while !matches!(self.peek(), None | Some(b'\n')) {
+            self.advance();
+        }
+        if self.line.is_empty() && self.peek().is_none() {
+            None
+        } else {
+            Some(self.line.clone())
+        }
+    }
+}
+
+/// longest stderr kept for a case
+const STDERR_LEN: u64 = 4096;
+
+fn read_stderr(err_path: &str) -> Option<String> {
+    let file = File::open(err_path).ok()?;
+    let size = file.metadata().ok()?.len();
+    if size == 0 {
+        return None;
+    }
+    let mut content = Vec::new();
+    file.take(STDERR_LEN).read_to_end(&mut content).ok()?;
+    let mut stderr = String::from_utf8_lossy(&content).to_string();
+    if size > STDERR_LEN {
+        stderr += "...";
+    }
+    Some(stderr)
+}
+
+/// longest excerpt of a line shown in a diff
+const DIFF_EXCERPT_LEN: usize = 40;
+/// bytes kept of a line, enough to tell whether it is longer than the excerpt
+const DIFF_EXCERPT_BYTES: usize = (DIFF_EXCERPT_LEN + 1) * 4;
+
+fn excerpt(line: &Option<Vec<u8>>) -> String {
+    match line {
+        Some(line) => {
+            let line = String::from_utf8_lossy(line);
+            let mut excerpt: String = line.chars().take(DIFF_EXCERPT_LEN).collect();
+            if line.chars().count() > DIFF_EXCERPT_LEN {
+                excerpt += "...";
             }
+            format!("{:?}", excerpt)
+        }
+        None => "end of output".to_string()
+    }
+}
 
-            update_job(&conn, &job).unwrap();
-            // judge
-            if job.cases[0].result == "Compilation Success" {
-                let mut cases_count = 0;
-                for case in &cases {
-                    let result = tempdir.judge(case, &problem.ty).await;
-                    cases_count += 1;
-                    
-                    job.cases[cases_count].result = result.clone();
-                    if result == "Accepted" {
-                        job.score += case.score;
-                    }
-                    if job.result == "Waiting" || job.result == "Accepted" {
-                        job.result = result;
+/// decides which cases are skipped after a failure
+struct CaseGroups {
+    /// group of every case, each case forms its own group without packing
+    groups: Vec<usize>,
+    /// skip all cases after the first failed one
+    stop_on_failure: bool
+}
+
+impl CaseGroups {
+    fn new(problem: &Problem) -> CaseGroups {
+        let mut groups: Vec<usize> = (0..problem.cases.len()).collect();
+        let packing = problem.misc.as_ref().and_then(|misc| misc.packing.as_ref());
+        if let Some(packing) = packing {
+            for (group_id, group) in packing.iter().enumerate() {
+                // case ids in packing start from 1
+                for case_id in group {
+                    if *case_id >= 1 && *case_id <= groups.len() {
+                        groups[case_id - 1] = problem.cases.len() + group_id;
                     }
-                    update_job(&conn, &job).unwrap();
                 }
             }
-            job.state = "Finished".to_string();
-            update_job(&conn, &job).unwrap();
+        }
+        let stop_on_failure = problem.misc.as_ref()
+            .and_then(|misc| misc.stop_on_failure)
+            .unwrap_or(false);
+        CaseGroups { groups, stop_on_failure }
+    }
+
+    /// `failed` maps a group to its first failed case
+    fn skipped(&self, failed: &HashMap<usize, usize>, index: usize) -> bool {
+        failed.iter().any(|(group, first)| {
+            *first < index && (self.stop_on_failure || *group == self.groups[index])
+        })
+    }
+
+    fn fail(&self, failed: &mut HashMap<usize, usize>, index: usize) {
+        let first = failed.entry(self.groups[index]).or_insert(index);
+        *first = (*first).min(index);
+    }
+}
+
+/// checker of spj problems
+struct Checker {
+    command: Vec<String>,
+    testlib: bool
+}
+
+/// everything the cases of a job share while running
+struct CaseContext {
+    tempdir: Tempdir,
+    ty: String,
+    /// only for spj problems
+    checker: Option<Checker>,
+    /// only practice jobs may show where the output differs
+    reveal_diff: bool,
+    /// used by cases without their own output limit
+    output_limit: u64,
+    groups: CaseGroups,
+    /// first failed case of each group
+    failed: RefCell<HashMap<usize, usize>>,
+    workers: Arc<Semaphore>
+}
+
+/// cases running in parallel, aborted when the job stops waiting for them
+struct PendingCases(Vec<tokio::task::JoinHandle<Case>>);
+
+impl Drop for PendingCases {
+    fn drop(&mut self) {
+        for handle in &self.0 {
+            handle.abort();
+        }
+    }
+}
+
+/// run one case within the global worker budget
+async fn judge_case(context: Rc<CaseContext>, index: usize, case: config::Case) -> Case {
+    let _permit = context.workers.clone().acquire_owned().await.unwrap();
+    if context.groups.skipped(&context.failed.borrow(), index) {
+        let mut result = Case::new(index + 1);
+        result.result = "Skipped".to_string();
+        return result;
+    }
+    let output_limit = if case.output_limit > 0 {
+        case.output_limit
+    } else {
+        context.output_limit
+    };
+    let result = context.tempdir.judge(
+        index + 1,
+        &case,
+        &context.ty,
+        &context.checker,
+        output_limit,
+        context.reveal_diff
+    ).await;
+    if result.result != "Accepted" {
+        context.groups.fail(&mut context.failed.borrow_mut(), index);
+    }
+    result
+}
+
+/// save the result of a case, cases after a failure are skipped
+fn record_case(
+    job: &mut JobContent,
+    cases: &[config::Case],
+    groups: &CaseGroups,
+    failed: &mut HashMap<usize, usize>,
+    index: usize,
+    mut result: Case
+) {
+    if groups.skipped(failed, index) {
+        result = Case::new(index + 1);
+        result.result = "Skipped".to_string();
+    } else if result.result != "Accepted" {
+        groups.fail(failed, index);
+    }
+    if job.result == "Waiting" || job.result == "Accepted" {
+        job.result = result.result.clone();
+    }
+    job.cases[index + 1] = result;
+    // a group scores only when all of its cases are accepted
+    job.score = (0..=index)
+        .filter(|x| {
+            job.cases[x + 1].result == "Accepted"
+                && !failed.contains_key(&groups.groups[*x])
+        })
+        .map(|x| cases[x].score)
+        // not sum(), which is -0.0 when nothing is accepted
+        .fold(0.0, |score, x| score + x);
+}
+
+/// remove run directories and lock files left behind
+/// by servers that are not running any more
+pub fn sweep_work_root(work_root: &str) {
+    if let Err(err) = claim_work_root(work_root) {
+        log::warn!("Failed to lock {}: {}", work_root, err);
+        return;
+    }
+    let entries = match fs::read_dir(work_root) {
+        Ok(entries) => entries,
+        Err(_) => return
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let pid = match name.strip_prefix(RUN_DIR_PREFIX) {
+            Some(rest) => rest.split(['-', '.']).next().and_then(|x| x.parse::<u32>().ok()),
+            None => continue
+        };
+        if let Some(pid) = pid {
+            if pid == std::process::id() || owner_running(work_root, pid) {
+                continue;
+            }
+        }
+        log::info!("Remove stale run directory {:?}", entry.path());
+        if entry.path().is_dir() {
+            fs::remove_dir_all(entry.path()).ok();
+        } else {
+            fs::remove_file(entry.path()).ok();
+        }
+    }
+}
+
+async fn judge_job(
+    job_id: usize,
+    storage: web::Data<dyn Storage>,
+    languages: Vec<Language>,
+    judge: config::Judge,
+    workers: web::Data<Semaphore>
+) {
+    let mut job = match storage.job(job_id) {
+        Ok(job) => job,
+        Err(_) => return
+    };
+    // the problem may have been changed since the submission
+    let problem = match storage.problem(job.submission.problem_id) {
+        Ok(problem) => problem,
+        Err(_) => return
+    };
+    let cases = problem.cases.clone();
+    // init cases state
+    job.cases = (0..=cases.len()).map(Case::new).collect();
+    if !start_job(storage.get_ref(), &mut job).unwrap() {
+        log::info!("Job {} was taken by another judge", job_id);
+        return;
+    }
+    // a reload may have removed the language since the submission
+    let language = match languages.iter().find(|x| x.name == job.submission.language) {
+        Some(language) => language,
+        None => {
+            log::error!("Language {} of job {} is not configured", job.submission.language, job_id);
+            job.cases[0].result = "System Error".to_string();
+            job.cases[0].info = "Language ".to_string() + &job.submission.language + " is not configured.";
+            job.result = "System Error".to_string();
+            if update_running_job(storage.get_ref(), &mut job, 0).unwrap() {
+                finish_job(storage.get_ref(), &mut job).unwrap();
+            }
+            return;
+        }
+    };
+    //init temporary directory
+    let mut tempdir = match Tempdir::new(&judge.work_root(), job_id) {
+        Ok(tempdir) => tempdir,
+        Err(err) => {
+            log::error!("Failed to create temporary directory for job {}: {}", job_id, err);
+            job.result = "System Error".to_string();
+            finish_job(storage.get_ref(), &mut job).unwrap();
+            return;
+        }
+    };
+
+    // compilate
+    let cache = judge.compile_cache.as_ref().map(CompileCache::new);
+    let source_code = job.submission.source_bytes().unwrap_or_default();
+    if tempdir.compilate(language, &source_code, &cache).await{
+        job.cases[0].result = "Compilation Success".to_string();
+    }else {
+        job.cases[0].result = "Compilation Error".to_string();
+        job.result = "Compilation Error".to_string();
+    }
 
-            tempdir.delete_tempdir().await;
-            
+    if !update_running_job(storage.get_ref(), &mut job, 0).unwrap() {
+        return;
+    }
+    // judge
+    if job.cases[0].result == "Compilation Success" {
+        let context = Rc::new(CaseContext {
+            tempdir,
+            ty: problem.ty.clone(),
+            checker: problem.misc.as_ref()
+                .filter(|_| problem.ty == "spj")
+                .and_then(|misc| {
+                    misc.special_judge.clone().map(|command| Checker {
+                        command,
+                        testlib: misc.testlib_checker.unwrap_or(false)
+                    })
+                }),
+            reveal_diff: job.submission.contest_id == 0 && problem.misc.as_ref()
+                .and_then(|misc| misc.reveal_diff)
+                .unwrap_or(false),
+            output_limit: judge.output_limit(),
+            groups: CaseGroups::new(&problem),
+            failed: RefCell::new(HashMap::new()),
+            workers: workers.clone().into_inner()
+        });
+        let parallel = problem.misc.as_ref()
+            .and_then(|misc| misc.parallel_cases)
+            .unwrap_or(false);
+        // first failed case of each group in case order
+        let mut group_failed: HashMap<usize, usize> = HashMap::new();
+        let mut pending = PendingCases(Vec::new());
+        for (index, case) in cases.iter().enumerate() {
+            let task = judge_case(context.clone(), index, case.clone());
+            if parallel {
+                pending.0.push(actix_web::rt::spawn(task));
+            } else {
+                let result = task.await;
+                record_case(&mut job, &cases, &context.groups, &mut group_failed, index, result);
+                if !update_running_job(storage.get_ref(), &mut job, index + 1).unwrap() {
+                    return;
+                }
+            }
+        }
+        // results are written in case order, returning early
+        // aborts the cases that are left
+        for (index, handle) in pending.0.iter_mut().enumerate() {
+            let result = handle.await.unwrap_or_else(|_| {
+                let mut result = Case::new(index + 1);
+                result.result = "System Error".to_string();
+                result
+            });
+            record_case(&mut job, &cases, &context.groups, &mut group_failed, index, result);
+            if !update_running_job(storage.get_ref(), &mut job, index + 1).unwrap() {
+                return;
+            }
         }
-        Err(_) => ()
     }
+    finish_job(storage.get_ref(), &mut job).unwrap();
 }
 
 #[post("/jobs")]
 #[allow(unreachable_code)]
 async fn post_jobs(
     body: web::Json<PostJob>, 
-    config: web::Data<Config>,
-    pool: web::Data<Pool<SqliteConnectionManager>>
+    config: web::Data<SharedConfig>,
+    storage: web::Data<dyn Storage>,
+    workers: web::Data<Semaphore>
 ) -> impl Responder {
+    let config = config.get();
 
-    let conn = pool.get().unwrap();
-    let job_list_raw = query_jobs(&conn).unwrap();
 
+    // init content, the id is allocated when the job is inserted
+    let mut content = JobContent::new(0);
 
-    // init content
-    let mut content = JobContent::new(job_list_raw.len());
-
-    log::info!("Post job {}", content.id);
+    log::info!("Post job for problem {}", body.problem_id);
 
     content.submission = body.clone();
     // time
-    let fmt = "%Y-%m-%dT%H:%M:%S%.3fZ";
-    let now: DateTime<Utc> = Utc::now();
-    content.created_time = now.format(fmt).to_string();
-    content.updated_time = content.created_time.clone();
+    content.created_time = timestamp::now();
+    content.updated_time = content.created_time;
     content.state = "Queueing".to_string();
     
-    let problems = &config.problems;
     let languages = &config.languages;
 
-    // check language
-    let mut check_language = false;
-    for index in 0..languages.len() {
-        if body.language == languages[index].name {
-            check_language = true;
-            break;
+    // check source code
+    let source_code = match body.source_bytes() {
+        Some(source_code) => source_code,
+        None => {
+            return HttpResponse::BadRequest().json({
+                ErrorMessage{
+                    code: (1),
+                    reason: ("ERR_INVALID_ARGUMENT".to_string()),
+                    message: ("Invalid source code encoding.".to_string())
+                }
+            });
         }
+    };
+    if source_code.len() > config.judge.max_source_size() {
+        return HttpResponse::BadRequest().json({
+            ErrorMessage{
+                code: (1),
+                reason: ("ERR_INVALID_ARGUMENT".to_string()),
+                message: ("Source code is too large.".to_string())
+            }
+        });
     }
+
+    // check language
+    let check_language = languages.iter().any(|language| body.language == language.name);
     if !check_language {
         return HttpResponse::NotFound().json({
             ErrorMessage{
@@ -363,55 +910,53 @@ async fn post_jobs(
 
 
     // check problem id
-    let mut check_problem_id = false;
-    let mut problem = &Problem::new();
-    for index in 0..problems.len() {
-        problem = &problems[index];
-        if body.problem_id == problem.id {
-            check_problem_id = true;
-            break;
+    let problem = match storage.problem(body.problem_id) {
+        Ok(problem) => problem,
+        Err(_) => {
+            return HttpResponse::NotFound().json({
+                ErrorMessage{
+                    code: (3),
+                    reason: ("ERR_NOT_FOUND".to_string()),
+                    message: ("Problem ".to_string() + &body.problem_id.to_string() + " not found.")
+                }
+            });
         }
-    }
-    if !check_problem_id {
-        return HttpResponse::NotFound().json({
-            ErrorMessage{
-                code: (3),
-                reason: ("ERR_NOT_FOUND".to_string()),
-                message: ("Problem ".to_string() + &body.problem_id.to_string() + " not found.")
-            }
-        });
-    }
+    };
 
     // check user id
-    let user_list = query_users(&conn).unwrap();
-    if body.user_id >= user_list.len() {
-        return HttpResponse::NotFound().json({
-            ErrorMessage{
-                code: (3),
-                reason: ("ERR_NOT_FOUND".to_string()),
-                message: ("User ".to_string() + &body.user_id.to_string() + " not found.")
-            }
-        });
-    }
+    let user = match storage.user(body.user_id) {
+        Ok(user) => user,
+        Err(_) => {
+            return HttpResponse::NotFound().json({
+                ErrorMessage{
+                    code: (3),
+                    reason: ("ERR_NOT_FOUND".to_string()),
+                    message: ("User ".to_string() + &body.user_id.to_string() + " not found.")
+                }
+            });
+        }
+    };
 
-    // check contest id
-    let contest_list = query_contests(&conn).unwrap();
-    if body.contest_id > contest_list.len() {
-        return HttpResponse::NotFound().json({
-            ErrorMessage{
-                code: (3),
-                reason: ("ERR_NOT_FOUND".to_string()),
-                message: ("Contest ".to_string() + &body.contest_id.to_string() + " not found.")
+    // check contest id, 0 is not a contest
+    let contest = match body.contest_id {
+        0 => None,
+        contest_id => match storage.contest(contest_id) {
+            Ok(contest) => Some(contest),
+            Err(_) => {
+                return HttpResponse::NotFound().json({
+                    ErrorMessage{
+                        code: (3),
+                        reason: ("ERR_NOT_FOUND".to_string()),
+                        message: ("Contest ".to_string() + &contest_id.to_string() + " not found.")
+                    }
+                });
             }
-        });
-    }
-
-    
+        }
+    };
 
     // check contest
-    if body.contest_id > 0 {
+    if let Some(contest) = &contest {
         //user problem not in contest
-        let contest = &contest_list[body.contest_id - 1];
         if !contest.user_ids.contains(&body.user_id) {
             return HttpResponse::BadRequest().json({
                 ErrorMessage{
@@ -431,10 +976,7 @@ async fn post_jobs(
             });
         }
         // contest time
-        let created_time = NaiveDateTime::parse_from_str(&content.created_time, fmt).expect("failed to get time");
-        let contest_from_time = NaiveDateTime::parse_from_str(&contest.from, fmt).expect("failed to get time");
-        let contest_to_time = NaiveDateTime::parse_from_str(&contest.to, fmt).expect("failed to get time");
-        if contest_from_time > created_time {
+        if contest.from > content.created_time {
             return HttpResponse::BadRequest().json({
                 ErrorMessage{
                     code: (1),
@@ -444,7 +986,7 @@ async fn post_jobs(
             });
         }
 
-        if contest_to_time < created_time {
+        if contest.to < content.created_time {
             return HttpResponse::BadRequest().json({
                 ErrorMessage{
                     code: (1),
@@ -456,7 +998,11 @@ async fn post_jobs(
 
         // check submission count
         if contest.submission_limit > 0 {
-            let user = &user_list[body.user_id];
+            let job_list_raw = storage.jobs(&QueryJob {
+                contest_id: Some(body.contest_id),
+                user_id: Some(user.id),
+                ..QueryJob::default()
+            }).unwrap();
             let mut job_list =Vec::new();
             for job in &*job_list_raw {
                 if check_job(job, contest) {
@@ -480,145 +1026,155 @@ async fn post_jobs(
     for case_id in 0..=cases.len() {
         content.cases.push(Case::new(case_id));
     }
-    // run job
-    actix_web::rt::spawn(judge_job(content.id, pool.clone(), problems.clone(), languages.clone()));
-
-    // save all jobs
-    insert_job(&conn, &content).unwrap();
+    // save the job, then run it
+    storage.insert_job(&mut content).unwrap();
+    log::info!("Job {} created", content.id);
+    actix_web::rt::spawn(judge_job(content.id, storage.clone(), languages.clone(), config.judge.clone(), workers.clone()));
 
     HttpResponse::Ok().json(content)
 }
 
-#[derive(Clone, Deserialize, Serialize)]
-struct QueryJob {
-    user_id: Option<usize>,
-    user_name: Option<String>,
-    contest_id: Option<usize>,
-    problem_id: Option<usize>,
-    language: Option<String>,
-    from: Option<String>,
-    to: Option<String>,
-    state: Option<String>,
-    result: Option<String>
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct QueryJob {
+    pub user_id: Option<usize>,
+    pub user_name: Option<String>,
+    pub contest_id: Option<usize>,
+    pub problem_id: Option<usize>,
+    pub language: Option<String>,
+    #[serde(default, with = "timestamp::option")]
+    pub from: Option<DateTime<Utc>>,
+    #[serde(default, with = "timestamp::option")]
+    pub to: Option<DateTime<Utc>>,
+    pub state: Option<String>,
+    pub result: Option<String>,
+    /// at most this many jobs
+    pub limit: Option<usize>,
+    /// skip this many jobs first
+    pub offset: Option<usize>,
+    /// one of id, created_time, updated_time and score, id when missing
+    pub order_by: Option<String>,
+    /// asc or desc, asc when missing
+    pub order: Option<String>,
+    /// load the stderr of every job, otherwise only where `hide_stderr` keeps it
+    #[serde(skip)]
+    pub admin: bool
 }
 impl QueryJob {
-    fn match_job(&self, job: &JobContent, conn: &PooledConnection<SqliteConnectionManager>) -> bool{
-        match self.user_id {
-            Some(user_id) => {
-                if user_id != job.submission.user_id {
-                    return false;
-                }
-            }
-            None => ()
-        }
-        match &self.user_name{
-            Some(user_name) => {
-                let user_list = query_users(conn).unwrap();
-                let user_id = job.submission.user_id;
-                if user_name != &user_list[user_id].name {
-                    return false;
-                }
-            }
-            None => ()
+    /// the column to sort by, and whether it is descending
+    pub fn order(&self) -> std::result::Result<(&'static str, bool), String> {
+        let order_by = match self.order_by.as_deref().unwrap_or("id") {
+            "id" => "id",
+            "created_time" => "created_time",
+            "updated_time" => "updated_time",
+            "score" => "score",
+            order_by => return Err("Invalid order_by ".to_string() + order_by + ".")
+        };
+        match self.order.as_deref().unwrap_or("asc") {
+            "asc" => Ok((order_by, false)),
+            "desc" => Ok((order_by, true)),
+            order => Err("Invalid order ".to_string() + order + ".")
         }
-        match self.contest_id {
-            Some(contest_id) => {
-                if contest_id != job.submission.contest_id {
-                    return false;
-                }
-            }
-            None => ()
+    }
+
+    /// the filters as a query on jobs, joined with users for user_name
+    pub fn to_sql(&self) -> std::result::Result<(String, Vec<Value>), String> {
+        let mut sql = "SELECT jobs.* FROM jobs".to_string();
+        let mut conditions: Vec<&str> = Vec::new();
+        let mut values: Vec<Value> = Vec::new();
+        if let Some(user_name) = &self.user_name {
+            sql += " JOIN users ON users.id = jobs.user_id";
+            conditions.push("users.name = ?");
+            values.push(Value::Text(user_name.clone()));
         }
-        match self.problem_id {
-            Some(problem_id) => {
-                if problem_id != job.submission.problem_id {
-                    return false;
-                }
-            }
-            None => ()
+        if let Some(user_id) = self.user_id {
+            conditions.push("jobs.user_id = ?");
+            values.push(Value::Integer(user_id as i64));
         }
-        match &self.language {
-            Some(language) => {
-                if language != &job.submission.language {
-                    return false;
-                }
-            }
-            None => ()
+        if let Some(contest_id) = self.contest_id {
+            conditions.push("jobs.contest_id = ?");
+            values.push(Value::Integer(contest_id as i64));
         }
-        match &self.state {
-            Some(state) => {
-                if state != &job.state {
-                    return false;
-                }
-            }
-            None => ()
+        if let Some(problem_id) = self.problem_id {
+            conditions.push("jobs.problem_id = ?");
+            values.push(Value::Integer(problem_id as i64));
         }
-        match &self.result {
-            Some(result) => {
-                if result != &job.result {
-                    return false;
-                }
-            }
-            None => ()
-        }
-        let fmt = "%Y-%m-%dT%H:%M:%S%.3fZ";
-        match &self.from {
-            Some(from) => {
-                let from_time = NaiveDateTime::parse_from_str(from, fmt)
-                    .expect("failed to get time");
-                let creat_time = NaiveDateTime::parse_from_str(&job.created_time, fmt)
-                    .expect("failed to get time");
-                if from_time > creat_time {
-                    return false;
-                }
-            }
-            None => ()
-        }   
-        match &self.to {
-            Some(to) => {
-                let to_time = NaiveDateTime::parse_from_str(to, fmt)
-                    .expect("failed to get time");
-                let creat_time = NaiveDateTime::parse_from_str(&job.created_time, fmt)
-                    .expect("failed to get time");
-                if to_time < creat_time {
-                    return false;
-                }
+        if let Some(language) = &self.language {
+            conditions.push("jobs.language = ?");
+            values.push(Value::Text(language.clone()));
+        }
+        if let Some(state) = &self.state {
+            conditions.push("jobs.state = ?");
+            values.push(Value::Text(state.clone()));
+        }
+        if let Some(result) = &self.result {
+            conditions.push("jobs.result = ?");
+            values.push(Value::Text(result.clone()));
+        }
+        // stored times are fixed width, so they compare as text
+        for (time, condition) in [(self.from, "jobs.created_time >= ?"), (self.to, "jobs.created_time <= ?")] {
+            if let Some(time) = time {
+                conditions.push(condition);
+                values.push(Value::Text(timestamp::format(&time)));
             }
-            None => ()
-        }   
-        true
-    }
+        }
+        if !conditions.is_empty() {
+            sql += " WHERE ";
+            sql += &conditions.join(" AND ");
+        }
 
+        let (order_by, descending) = self.order()?;
+        let order = if descending { "DESC" } else { "ASC" };
+        sql += &format!(" ORDER BY jobs.{} {}, jobs.id {}", order_by, order, order);
+        if self.limit.is_some() || self.offset.is_some() {
+            // -1 means no limit
+            sql += " LIMIT ? OFFSET ?";
+            values.push(Value::Integer(self.limit.map(|x| x as i64).unwrap_or(-1)));
+            values.push(Value::Integer(self.offset.unwrap_or(0) as i64));
+        }
+        Ok((sql, values))
+    }
 }
 
 #[get("/jobs")]
 #[allow(unreachable_code)]
 async fn get_jobs(
+    http_request: HttpRequest,
     request: web::Query<QueryJob>,
-    pool: web::Data<Pool<SqliteConnectionManager>>
+    config: web::Data<SharedConfig>,
+    storage: web::Data<dyn Storage>
 ) -> impl Responder {
+    let config = config.get();
     log::info!("Get jobs");
-    let conn = pool.get().unwrap();
-    let job_list = query_jobs(&conn).unwrap();
-    let mut query_jobs: Vec<JobContent> = Vec::new();
-    for job in &job_list {
-        if request.match_job(job, &conn) {
-            query_jobs.push(job.clone());
-        }
-    }
-    HttpResponse::Ok().json(query_jobs)
+    let mut query = request.into_inner();
+    query.admin = config.is_admin(&http_request);
+    let job_list = match storage.jobs(&query) {
+        Ok(job_list) => job_list,
+        Err(StorageError::Invalid(message)) => return HttpResponse::BadRequest().json({
+            ErrorMessage{
+                code: (1),
+                reason: ("ERR_INVALID_ARGUMENT".to_string()),
+                message: (message)
+            }
+        }),
+        Err(err) => panic!("{}", err)
+    };
+    HttpResponse::Ok().json(job_list)
 }
 
 #[get("/jobs/{jobId}")]
 #[allow(unreachable_code)]
 async fn get_jobs_id(
+    http_request: HttpRequest,
     job_id: web::Path<usize>,
-    pool: web::Data<Pool<SqliteConnectionManager>>
+    config: web::Data<SharedConfig>,
+    storage: web::Data<dyn Storage>
 ) -> impl Responder {
+    let config = config.get();
     log::info!("Get jobs {}",job_id);
-    let conn = pool.get().unwrap();
-    match query_job(&conn, *job_id) {
-        Ok(job) => {
+    match storage.job(*job_id) {
+        Ok(mut job) => {
+            let problem = storage.problem(job.submission.problem_id).ok();
+            job.hide_stderr(config.is_admin(&http_request), problem.as_ref());
             HttpResponse::Ok().json(job)
         }
         Err(_) => HttpResponse::NotFound().json({
@@ -634,32 +1190,31 @@ async fn get_jobs_id(
 #[put("/jobs/{jobId}")]
 #[allow(unreachable_code)]
 async fn put_jobs(
+    http_request: HttpRequest,
     job_id: web::Path<usize>, 
-    config: web::Data<Config>,
-    pool: web::Data<Pool<SqliteConnectionManager>>
+    config: web::Data<SharedConfig>,
+    storage: web::Data<dyn Storage>,
+    workers: web::Data<Semaphore>
 ) -> impl Responder {
+    let config = config.get();
     log::info!("Put jobs {}", job_id);
-    let conn = pool.get().unwrap();
-    match query_job(&conn, *job_id) {
+    match storage.job(*job_id) {
         Ok(mut job) => {
-            if job.state != "Finished" {
-                return  HttpResponse::BadRequest().json({
+            // another rejudge may have requeued it first
+            if job.state != "Finished" || !requeue_job(storage.get_ref(), &mut job, "Finished").unwrap() {
+                HttpResponse::BadRequest().json({
                     ErrorMessage{
                         code: (2),
                         reason: ("ERR_INVALID_STATE".to_string()),
                         message: ("Job ".to_string() + &job_id.to_string() + " not finished.")
                     }
-                });
+                })
             }
             else {
-                job.state = "Queueing".to_string();
-                // time
-                let now: DateTime<Utc> = Utc::now();
-                job.updated_time = now.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string();
-                job.result = "Waiting".to_string();
-                job.score = 0.0;
-                actix_web::rt::spawn(judge_job(*job_id, pool.clone(), config.problems.clone(), config.languages.clone()));
-                return  HttpResponse::Ok().json(job);
+                actix_web::rt::spawn(judge_job(*job_id, storage.clone(), config.languages.clone(), config.judge.clone(), workers.clone()));
+                let problem = storage.problem(job.submission.problem_id).ok();
+                job.hide_stderr(config.is_admin(&http_request), problem.as_ref());
+                HttpResponse::Ok().json(job)
             }
         }
         Err(_) => HttpResponse::NotFound().json({
@@ -679,11 +1234,10 @@ async fn put_jobs(
 #[allow(unreachable_code)]
 async fn delete_jobs(
     job_id: web::Path<usize>,
-    pool: web::Data<Pool<SqliteConnectionManager>>
+    storage: web::Data<dyn Storage>
 ) -> impl Responder {
     log::info!("Delete jobs {}",job_id);
-    let conn = pool.get().unwrap();
-    match query_job(&conn, *job_id) {
+    match storage.job(*job_id) {
         Ok(job) => {
             if job.state != "Queueing" {
                 HttpResponse::NotFound().json({
@@ -694,7 +1248,7 @@ async fn delete_jobs(
                     }
                 })
             }else {
-                HttpResponse::Ok().json({})
+                HttpResponse::Ok().json(())
             }
         }
         Err(_) => HttpResponse::NotFound().json({
@@ -709,80 +1263,136 @@ async fn delete_jobs(
 }
 
 
-fn format_cases(v: &Vec<Case>) -> String {
-    v.iter()
-        .map(|x| format!("{}:{}:{}:{}:{}", x.id, x.result, x.time, x.memory, x.info))
-        .collect::<Vec<String>>()
-        .join(",")
+/// one row of job_cases per case, sorted by case id
+/// the cases of every job selected by a query on jobs, in one query,
+/// stderr is only read where `JobContent::hide_stderr` would keep it
+fn query_cases(conn: &Connection, sql: &str, values: &[Value], admin: bool) -> Result<HashMap<usize, Vec<Case>>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT job_cases.job_id, job_cases.id, job_cases.result, job_cases.time,
+            job_cases.memory, job_cases.info,
+            CASE WHEN ? OR (jobs.contest_id = 0 AND (
+                SELECT json_extract(misc, '$.show_stderr') FROM problems WHERE problems.id = jobs.problem_id
+            )) THEN job_cases.stderr END
+        FROM job_cases JOIN jobs ON jobs.id = job_cases.job_id
+        WHERE job_cases.job_id IN (SELECT id FROM ({})) ORDER BY job_cases.job_id, job_cases.id",
+        sql
+    ))?;
+    let mut rows = stmt.query(params_from_iter(std::iter::once(&Value::from(admin)).chain(values)))?;
+    let mut cases: HashMap<usize, Vec<Case>> = HashMap::new();
+    while let Some(row) = rows.next()? {
+        cases.entry(row.get(0)?).or_default().push(Case {
+            id: row.get(1)?,
+            result: row.get(2)?,
+            time: row.get(3)?,
+            memory: row.get(4)?,
+            info: row.get(5)?,
+            stderr: row.get(6)?
+        });
+    }
+    Ok(cases)
 }
 
-fn parse_cases(s: String) -> Vec<Case> {
-    s.split(',')
-        .map(|x| {
-            let v: Vec<&str> = x.split(':').collect();
-            Case {
-                id: usize::from_str(v[0]).unwrap(),
-                result: v[1].to_string(),
-                time: u64::from_str(v[2]).unwrap(),
-                memory: u64::from_str(v[3]).unwrap(),
-                info: v[4].to_string()
-            }
-        })
-        .collect()
+/// replace the stored cases of a job
+fn save_cases(conn: &Connection, job_id: usize, cases: &[Case]) -> Result<()> {
+    conn.execute("DELETE FROM job_cases WHERE job_id = ?", params![job_id])?;
+    let mut stmt = conn.prepare(
+        "INSERT INTO job_cases (job_id, id, result, time, memory, info, stderr)
+        VALUES (?, ?, ?, ?, ?, ?, ?)"
+    )?;
+    for case in cases {
+        stmt.execute(params![
+            job_id,
+            case.id,
+            case.result,
+            case.time,
+            case.memory,
+            case.info,
+            case.stderr
+        ])?;
+    }
+    Ok(())
 }
 
-pub fn query_jobs(conn: &Connection) -> Result<Vec<JobContent>> {
+/// source code is stored as TEXT, or as BLOB when it was base64 encoded
+fn read_source(row: &Row) -> Result<(String, Option<String>)> {
+    match row.get_ref(3)? {
+        ValueRef::Blob(bytes) => Ok((general_purpose::STANDARD.encode(bytes), Some("base64".to_string()))),
+        _ => Ok((row.get(3)?, None))
+    }
+}
 
-    let mut stmt = conn.prepare("SELECT * FROM jobs")?;
-
-    let rows = stmt.query_map([], |row| {
-        Ok(JobContent {
-            id: row.get(0)?,
-            created_time: row.get(1)?,
-            updated_time: row.get(2)?,
-            submission: PostJob {
-                source_code: row.get(3)?,
-                language: row.get(4)?,
-                user_id: row.get(5)?,
-                contest_id: row.get(6)?,
-                problem_id: row.get(7)?
-            },
-            state: row.get(8)?,
-            result: row.get(9)?,
-            score: row.get(10)?,
-            cases: parse_cases(row.get(11)?)
-        })
-    })?;
-    let jobs = rows.collect();
-    jobs
+fn read_job(row: &Row) -> Result<JobContent> {
+    let (source_code, source_encoding) = read_source(row)?;
+    Ok(JobContent {
+        id: row.get(0)?,
+        created_time: timestamp::read(row, 1)?,
+        updated_time: timestamp::read(row, 2)?,
+        submission: PostJob {
+            source_code,
+            source_encoding,
+            language: row.get(4)?,
+            user_id: row.get(5)?,
+            contest_id: row.get(6)?,
+            problem_id: row.get(7)?
+        },
+        state: row.get(8)?,
+        result: row.get(9)?,
+        score: row.get(10)?,
+        cases: Vec::new(),
+        version: row.get(11)?
+    })
+}
+
+/// run a query selecting jobs.* and load the cases of all the jobs,
+/// with stderr as `query_cases` reads it
+pub fn query_jobs_sql(conn: &Connection, sql: &str, values: &[Value], admin: bool) -> Result<Vec<JobContent>> {
+    // jobs and their cases are read from one snapshot,
+    // unless the caller already holds one
+    let tx = match conn.is_autocommit() {
+        true => Some(conn.unchecked_transaction()?),
+        false => None
+    };
+    let mut jobs: Vec<JobContent> = {
+        let mut stmt = conn.prepare(sql)?;
+        let rows = stmt.query_map(params_from_iter(values), read_job)?;
+        rows.collect::<Result<_>>()?
+    };
+    let mut cases = query_cases(conn, sql, values, admin)?;
+    for job in &mut jobs {
+        job.cases = cases.remove(&job.id).unwrap_or_default();
+    }
+    if let Some(tx) = tx {
+        tx.commit()?;
+    }
+    Ok(jobs)
+}
+
+pub fn query_jobs(conn: &Connection) -> Result<Vec<JobContent>> {
+    query_jobs_sql(conn, "SELECT * FROM jobs ORDER BY id", &[], true)
 }
 
 pub fn query_job(conn: &Connection, id: usize) -> Result<JobContent> {
-    let mut stmt = conn.prepare("SELECT * FROM jobs WHERE id = ?")?;
-    let mut jobs = stmt.query_map(params![id], |row| {
-        Ok(JobContent {
-            id: row.get(0)?,
-            created_time: row.get(1)?,
-            updated_time: row.get(2)?,
-            submission: PostJob {
-                source_code: row.get(3)?,
-                language: row.get(4)?,
-                user_id: row.get(5)?,
-                contest_id: row.get(6)?,
-                problem_id: row.get(7)?
-            },
-            state: row.get(8)?,
-            result: row.get(9)?,
-            score: row.get(10)?,
-            cases: parse_cases(row.get(11)?)
-        })
-    })?;
-    jobs.next().unwrap_or(Err(rusqlite::Error::QueryReturnedNoRows))
+    query_jobs_sql(conn, "SELECT * FROM jobs WHERE id = ?", &[Value::Integer(id as i64)], true)?
+        .pop()
+        .ok_or(rusqlite::Error::QueryReturnedNoRows)
 }
 
-fn insert_job(conn: &Connection, job: &JobContent) -> Result<()> {
+/// let the database allocate the id of the job and save it
+pub fn insert_job(conn: &Connection, job: &mut JobContent) -> Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    job.id = write_job(&tx, None, job)?;
+    tx.commit()
+}
+
+/// save a job and its cases with the id as is
+pub fn save_job(conn: &Connection, job: &JobContent) -> Result<()> {
+    write_job(conn, Some(job.id), job)?;
+    Ok(())
+}
 
-    conn.execute("INSERT INTO jobs (
+/// a missing id is allocated, return the id
+fn write_job(conn: &Connection, id: Option<usize>, job: &JobContent) -> Result<usize> {
+    conn.execute(&format!("INSERT INTO jobs (
         id, 
         created_time,
         updated_time, 
@@ -794,12 +1404,12 @@ fn insert_job(conn: &Connection, job: &JobContent) -> Result<()> {
         state, 
         result, 
         score, 
-        cases) 
-        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
-        params![job.id,
-        job.created_time,
-        job.updated_time,
-        job.submission.source_code,
+        version) 
+        VALUES (COALESCE(?, {}), ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)", migration::next_id_sql("jobs")),
+        params![id,
+        timestamp::format(&job.created_time),
+        timestamp::format(&job.updated_time),
+        job.submission.source_value(),
         job.submission.language,
         job.submission.user_id,
         job.submission.contest_id,
@@ -807,37 +1417,123 @@ fn insert_job(conn: &Connection, job: &JobContent) -> Result<()> {
         job.state,
         job.result,
         job.score,
-        format_cases(&job.cases)
+        job.version
     ])?;
-    Ok(())
+    let id = conn.last_insert_rowid() as usize;
+    save_cases(conn, id, &job.cases)?;
+    Ok(id)
 }
 
-fn update_job(conn: &Connection, job: &JobContent) -> Result<()> {
-
-    conn.execute("UPDATE jobs SET 
-        created_time = ?, 
-        updated_time = ?, 
-        source_code = ?, 
-        language = ?, user_id = ?, 
-        contest_id = ?, 
-        problem_id = ?, 
-        state = ?, 
-        result = ?, 
-        score = ?, 
-        cases = ? 
-        WHERE id = ?",
-        params![job.created_time,
-        job.updated_time,
-        job.submission.source_code,
-        job.submission.language,
-        job.submission.user_id,
-        job.submission.contest_id,
-        job.submission.problem_id,
-        job.state,
-        job.result,
-        job.score,
-        format_cases(&job.cases),
-        job.id
-    ])?;
+/// see `Storage::transition_job`
+pub fn transition_job(conn: &Connection, job: &mut JobContent, from: &str, case_ids: Option<&[usize]>) -> Result<bool> {
+    let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+    let updated_time = timestamp::now();
+    let changed = tx.execute(
+        "UPDATE jobs SET state = ?, result = ?, score = ?, updated_time = ?, version = version + 1
+        WHERE id = ? AND state = ? AND version = ?",
+        params![job.state, job.result, job.score, timestamp::format(&updated_time), job.id, from, job.version],
+    )?;
+    if changed == 0 {
+        return Ok(false);
+    }
+    match case_ids {
+        Some(case_ids) => {
+            for case_id in case_ids {
+                let case = &job.cases[*case_id];
+                tx.execute(
+                    "INSERT OR REPLACE INTO job_cases (job_id, id, result, time, memory, info, stderr)
+                    VALUES (?, ?, ?, ?, ?, ?, ?)",
+                    params![job.id, case.id, case.result, case.time, case.memory, case.info, case.stderr],
+                )?;
+            }
+        }
+        None => save_cases(&tx, job.id, &job.cases)?
+    }
+    tx.commit()?;
+    job.updated_time = updated_time;
+    job.version += 1;
+    Ok(true)
+}
+
+/// Queueing to Running, with fresh cases
+fn start_job(storage: &dyn Storage, job: &mut JobContent) -> storage::Result<bool> {
+    job.state = "Running".to_string();
+    storage.transition_job(job, "Queueing", None)
+}
+
+/// stay Running, after a case is judged
+fn update_running_job(storage: &dyn Storage, job: &mut JobContent, case_id: usize) -> storage::Result<bool> {
+    storage.transition_job(job, "Running", Some(&[case_id]))
+}
+
+/// Running to Finished
+fn finish_job(storage: &dyn Storage, job: &mut JobContent) -> storage::Result<bool> {
+    job.state = "Finished".to_string();
+    storage.transition_job(job, "Running", Some(&[]))
+}
+
+/// Finished to Queueing for a rejudge, or Running to Queueing
+/// when the server stopped while judging
+fn requeue_job(storage: &dyn Storage, job: &mut JobContent, from: &str) -> storage::Result<bool> {
+    job.state = "Queueing".to_string();
+    job.result = "Waiting".to_string();
+    job.score = 0.0;
+    job.cases = (0..job.cases.len()).map(Case::new).collect();
+    storage.transition_job(job, from, None)
+}
+
+/// judge the jobs a previous server left Running or Queueing,
+/// no other server may be judging with the same storage
+pub fn resume_jobs(
+    storage: web::Data<dyn Storage>,
+    languages: &[Language],
+    judge: &config::Judge,
+    workers: web::Data<Semaphore>
+) -> storage::Result<()> {
+    let running = QueryJob { state: Some("Running".to_string()), admin: true, ..Default::default() };
+    for mut job in storage.jobs(&running)? {
+        log::info!("Requeue job {} left Running", job.id);
+        requeue_job(storage.get_ref(), &mut job, "Running")?;
+    }
+    let queueing = QueryJob { state: Some("Queueing".to_string()), admin: true, ..Default::default() };
+    for job in storage.jobs(&queueing)? {
+        log::info!("Resume job {}", job.id);
+        actix_web::rt::spawn(judge_job(job.id, storage.clone(), languages.to_vec(), judge.clone(), workers.clone()));
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compare_ignores_trailing_spaces_and_empty_lines() {
+        assert_eq!(compare(&b"1 2 \r\n3\n\n \n"[..], &b"1 2\n3"[..], false), (true, String::new()));
+        assert_eq!(compare(&b"1 2"[..], &b"1 2\n\n"[..], false), (true, String::new()));
+        assert_eq!(
+            compare(&b"1 2\n"[..], &b"1 2\n3\n"[..], false),
+            (false, "line 2: expected \"3\", received end of output".to_string())
+        );
+        assert_eq!(
+            compare(&b"1  2\n"[..], &b"1 2\n"[..], false),
+            (false, "line 1: expected \"1 2\", received \"1  2\"".to_string())
+        );
+        assert_eq!(
+            compare(&b"1 2 \n"[..], &b"1 2\n"[..], true),
+            (false, "line 1: expected \"1 2\", received \"1 2 \"".to_string())
+        );
+        assert!(!compare(&b"1 2\n\n"[..], &b"1 2\n"[..], true).0);
+    }
+
+    #[test]
+    fn compare_keeps_an_excerpt_of_long_lines() {
+        let mut output = vec![b'1'; 1 << 20];
+        let mut answer = output.clone();
+        output.extend_from_slice(b" \n");
+        answer.extend_from_slice(b"2");
+        let (accepted, diff) = compare(&output[..], &answer[..], false);
+        assert!(!accepted);
+        assert_eq!(diff, format!("line 1: expected \"{0}...\", received \"{0}...\"", "1".repeat(DIFF_EXCERPT_LEN)));
+    }
+}
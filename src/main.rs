@@ -1,15 +1,16 @@
-use actix_web::{get, middleware::Logger, post, web, App, HttpServer, Responder, put, delete};
+use actix_web::{get, middleware::Logger, post, web, App, HttpRequest, HttpResponse, HttpServer, Responder, put, delete};
 use serde::{Deserialize, Serialize};
-use env_logger;
-use log;
 use r2d2::Pool;
 use r2d2_sqlite::SqliteConnectionManager;
 use rusqlite::{Connection, Result};
 use clap::Parser;
+use std::os::unix::fs::FileTypeExt;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::signal::unix::{signal, SignalKind};
 
 mod config;
-use config::Config;
-use config::Cli;
+use config::{Cli, Command, SharedConfig};
 
 mod job;
 use job::{
@@ -26,6 +27,32 @@ use user::{
     get_users
 };
 
+mod cache;
+
+mod migration;
+
+mod archive;
+
+mod storage;
+use storage::{MemoryStorage, SqliteStorage, Storage};
+
+mod timestamp;
+
+mod package;
+use package::Package;
+
+mod polygon;
+
+mod problem;
+use problem::{
+    post_problems,
+    post_problems_import,
+    get_problems,
+    get_problems_id,
+    put_problems,
+    delete_problems
+};
+
 mod contest;
 use contest::{
     post_contests,
@@ -42,6 +69,22 @@ struct ErrorMessage {
     message: String
 }
 
+fn invalid_argument(message: String) -> HttpResponse {
+    HttpResponse::BadRequest().json({
+        ErrorMessage{
+            code: (1),
+            reason: ("ERR_INVALID_ARGUMENT".to_string()),
+            message: (message)
+        }
+    })
+}
+
+/// malformed bodies and queries, such as bad times, as ERR_INVALID_ARGUMENT
+fn malformed_request<E: std::fmt::Display + std::fmt::Debug + 'static>(err: E) -> actix_web::Error {
+    let response = invalid_argument(err.to_string());
+    actix_web::error::InternalError::from_response(err, response).into()
+}
+
 #[get("/hello/{name}")]
 async fn greet(name: web::Path<String>) -> impl Responder {
     log::info!(target: "greet_handler", "Greeting {}", name);
@@ -54,34 +97,147 @@ async fn greet(name: web::Path<String>) -> impl Responder {
 async fn exit() -> impl Responder {
     log::info!("Shutdown as requested");
     std::process::exit(0);
-    format!("Exited")
+    "Exited"
+}
+
+/// reload config.json, the problems in it replace the stored ones
+fn reload_config(config: &SharedConfig, storage: &dyn Storage) -> std::result::Result<(), Vec<String>> {
+    let config = config.reload()?;
+    problem::init_problems(storage, &config.problems).map_err(|err| vec![err.to_string()])?;
+    log::info!("Config reloaded");
+    Ok(())
+}
+
+#[post("/admin/reload")]
+#[allow(unreachable_code)]
+async fn reload(
+    http_request: HttpRequest,
+    config: web::Data<SharedConfig>,
+    storage: web::Data<dyn Storage>
+) -> impl Responder {
+    log::info!("Reload config as requested");
+    if !config.get().is_admin(&http_request) {
+        return HttpResponse::Forbidden().json({
+            ErrorMessage{
+                code: (7),
+                reason: ("ERR_FORBIDDEN".to_string()),
+                message: ("Only admins can reload the config.".to_string())
+            }
+        });
+    }
+    match reload_config(&config, storage.get_ref()) {
+        Ok(()) => HttpResponse::Ok().json(()),
+        Err(errors) => HttpResponse::BadRequest().json({
+            ErrorMessage{
+                code: (1),
+                reason: ("ERR_INVALID_ARGUMENT".to_string()),
+                message: (errors.join("; "))
+            }
+        })
+    }
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     let cli = Cli::parse();
     let config =  cli.init_config()?;
+    // report every mistake before anything starts
+    let errors = config.validate();
+    for error in &errors {
+        eprintln!("{}", error);
+    }
+    if !errors.is_empty() {
+        std::process::exit(1);
+    }
+    if cli.check_config {
+        println!("Config is valid.");
+        return Ok(());
+    }
+    // flushing first would leave them nothing to work on
+    if cli.flush_data && matches!(
+        cli.command,
+        Some(Command::Export { .. } | Command::Restore { .. } | Command::Backup { .. })
+    ) {
+        eprintln!("--flush-data cannot be used with export, restore or backup.");
+        std::process::exit(1);
+    }
+    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+
     // user database
-    let manager = SqliteConnectionManager::file("database/data.db");
-    let pool = Pool::builder().build(manager).unwrap();
-    let conn = pool.get().unwrap();
-    flush_user_table(&conn, cli.flush_data).unwrap();
-    create_user_table(&conn).unwrap();
-    user::init_root_user(&conn)?;
+    let storage: Arc<dyn Storage> = if config.database.backend() == "memory" {
+        if cli.command.is_some() {
+            eprintln!("Commands need the sqlite backend.");
+            std::process::exit(1);
+        }
+        Arc::new(MemoryStorage::new())
+    } else {
+        match open_sqlite(&cli, &config.database.path())? {
+            Some(pool) => Arc::new(SqliteStorage::new(pool)),
+            // a command was run
+            None => return Ok(())
+        }
+    };
+    user::init_root_user(storage.as_ref())?;
+    problem::init_problems(storage.as_ref(), &config.problems).unwrap();
+
+    if let Some(Command::Import { path }) = &cli.command {
+        let problem = Package::open(path, &config.judge).and_then(|mut package| {
+            package.load(&config.judge.data_dir(), storage.as_ref(), &config.languages)
+        });
+        match problem {
+            Ok(problem) => {
+                storage.save_problem(&problem).unwrap();
+                println!("Imported problem {} {}", problem.id, problem.name);
+                return Ok(());
+            }
+            Err(message) => {
+                eprintln!("{}", message);
+                std::process::exit(1);
+            }
+        }
+    }
 
+    // a previous server may have stopped while judging
+    job::sweep_work_root(&config.judge.work_root());
+
+    // shared by all judge tasks
+    let workers = web::Data::new(Semaphore::new(config.judge.workers()));
+    job::resume_jobs(web::Data::from(storage.clone()), &config.languages, &config.judge, workers.clone()).unwrap();
 
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
-    
     
-    HttpServer::new(move || {
+    let bind_addresses = config.bind_addresses();
+    let max_package_size = config.judge.max_package_size();
+    let unix_socket = config.unix_socket();
+    let config = web::Data::new(SharedConfig::new(cli.clone(), config));
+
+    // SIGHUP reloads the config
+    let mut hangup = signal(SignalKind::hangup())?;
+    let reload_handle = (config.clone(), storage.clone());
+    actix_web::rt::spawn(async move {
+        while hangup.recv().await.is_some() {
+            log::info!("Reload config on SIGHUP");
+            if let Err(errors) = reload_config(&reload_handle.0, reload_handle.1.as_ref()) {
+                for error in errors {
+                    log::error!("{}", error);
+                }
+            }
+        }
+    });
+
+    let mut server = HttpServer::new(move || {
         App::new()
-            .app_data(web::Data::new(config.clone()))
-            .app_data(web::Data::new(pool.clone()))
+            .app_data(config.clone())
+            .app_data(web::Data::from(storage.clone()))
+            .app_data(workers.clone())
+            .app_data(web::PayloadConfig::new(max_package_size))
+            .app_data(web::JsonConfig::default().error_handler(|err, _| malformed_request(err)))
+            .app_data(web::QueryConfig::default().error_handler(|err, _| malformed_request(err)))
             .wrap(Logger::default())
             .route("/hello", web::get().to(|| async { "Hello World!" }))
             .service(greet)
             // DO NOT REMOVE: used in automatic testing
             .service(exit)
+            .service(reload)
             .service(post_jobs)
             .service(get_jobs)
             .service(get_jobs_id)
@@ -93,60 +249,143 @@ async fn main() -> std::io::Result<()> {
             .service(get_contests)
             .service(get_contests_id)
             .service(get_contests_id_ranklist)
+            .service(post_problems)
+            .service(post_problems_import)
+            .service(get_problems)
+            .service(get_problems_id)
+            .service(put_problems)
+            .service(delete_problems)
             
-    })
-    .bind(("127.0.0.1", 12345))?
-    .run()
-    .await
+    });
+    for address in &bind_addresses {
+        server = server.bind((address.0.as_str(), address.1))?;
+    }
+    if let Some(path) = unix_socket {
+        // a socket left by a previous server blocks binding,
+        // anything else at the path is left alone
+        match std::fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(&path)?,
+            Ok(_) => {
+                eprintln!("{} exists and is not a socket.", path);
+                std::process::exit(1);
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err)
+        }
+        server = server.bind_uds(&path)?;
+    }
+    server.run().await
 }
 
-fn create_user_table(conn: &Connection) -> Result<()> {
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS users (
-            id INTEGER PRIMARY KEY,
-            name TEXT NOT NULL
-        )",
-        [],
-    )?;
-    // problem_ids and user_ids need vec to test
-    // when read, we need vec to test
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS contests (
-            id INTEGER PRIMARY KEY,
-            name TEXT NOT NULL,
-            'from' TEXT NOT NULL,
-            'to' TEXT NOT NULL,
-            problem_ids TEXT NOT NULL,
-            user_ids TEXT NOT NULL,
-            submission_limit INTEGER NOT NULL
-        )",
-        [],
-    )?;
-    // case need vec to test
-    // when read, we need vec to test
-    // sunmission divide into 5 parts
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS jobs (
-            id INTEGER PRIMARY KEY,
-            created_time TEXT NOT NULL,
-            updated_time TEXT NOT NULL,
-            source_code TEXT NOT NULL,
-            language TEXT NOT NULL,
-            user_id INTEGER NOT NULL,
-            contest_id INTEGER NOT NULL,
-            problem_id INTEGER NOT NULL,
-            state TEXT NOT NULL,
-            result TEXT NOT NULL,
-            score REAL NOT NULL,
-            cases TEXT NOT NULL
-        )",
-        [],
-    )?;
-    Ok(())
+/// open the database, migrate it and run the commands working on it,
+/// None when a command was run
+fn open_sqlite(cli: &Cli, path: &str) -> std::io::Result<Option<Pool<SqliteConnectionManager>>> {
+    let pool = open_database(path)?;
+    let mut conn = pool.get().unwrap();
+    if let Some(Command::Backup { path }) = &cli.command {
+        match archive::backup(&conn, path) {
+            Ok(()) => {
+                println!("Backed up to {}", path);
+                return Ok(None);
+            }
+            Err(message) => {
+                eprintln!("{}", message);
+                std::process::exit(1);
+            }
+        }
+    }
+    flush_user_table(&conn, cli.flush_data).unwrap();
+    if let Some(Command::Migrate { status: true }) = &cli.command {
+        let version = migration::current_version(&conn).unwrap();
+        println!("Schema version {}, latest {}", version, migration::latest_version());
+        for step in migration::pending(&conn).unwrap() {
+            println!("Pending {}: {}", step.version, step.description);
+        }
+        return Ok(None);
+    }
+    if migration::current_version(&conn).unwrap() > migration::latest_version() {
+        eprintln!("The database was created by a newer version of the server.");
+        std::process::exit(1);
+    }
+    let applied = match migration::migrate(&mut conn) {
+        Ok(applied) => applied,
+        Err(err) => {
+            eprintln!("Failed to migrate the database: {}", err);
+            std::process::exit(1);
+        }
+    };
+    if let Some(Command::Migrate { .. }) = &cli.command {
+        for step in &applied {
+            println!("Applied {}: {}", step.version, step.description);
+        }
+        println!("Schema version {}", migration::current_version(&conn).unwrap());
+        return Ok(None);
+    }
+    match &cli.command {
+        Some(Command::Export { path }) => {
+            match archive::export(&conn, path) {
+                Ok(count) => {
+                    println!("Exported {} rows to {}", count, path);
+                    return Ok(None);
+                }
+                Err(message) => {
+                    eprintln!("{}", message);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(Command::Restore { path }) => {
+            match archive::restore(&mut conn, path) {
+                Ok(count) => {
+                    println!("Restored {} rows from {}", count, path);
+                    return Ok(None);
+                }
+                Err(message) => {
+                    eprintln!("{}", message);
+                    std::process::exit(1);
+                }
+            }
+        }
+        _ => ()
+    }
+    drop(conn);
+    Ok(Some(pool))
+}
+
+fn open_database(path: &str) -> std::io::Result<Pool<SqliteConnectionManager>> {
+    if path == ":memory:" {
+        // all connections share one database, which lives as long as
+        // any of them, so they are never closed
+        let uri = format!("file:oj-{}?mode=memory&cache=shared", std::process::id());
+        let pool = Pool::builder()
+            .max_lifetime(None)
+            .idle_timeout(None)
+            .build(SqliteConnectionManager::file(uri).with_init(enable_foreign_keys))
+            .unwrap();
+        return Ok(pool);
+    }
+    if let Some(parent) = std::path::Path::new(path).parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    Ok(Pool::builder().build(SqliteConnectionManager::file(path).with_init(enable_foreign_keys)).unwrap())
 }
 
+/// sqlite only enforces foreign keys when asked to, per connection
+fn enable_foreign_keys(conn: &mut Connection) -> Result<()> {
+    conn.execute_batch("PRAGMA foreign_keys = ON")
+}
+
+/// tables are dropped after the ones referring to them
 fn flush_user_table(conn: &Connection, flush: bool) -> Result<()>{
     if flush {
+        conn.execute(
+            "DROP TABLE IF EXISTS contest_problems" , 
+            [],
+        )?;
+        conn.execute(
+            "DROP TABLE IF EXISTS contest_users" , 
+            [],
+        )?;
         conn.execute(
             "DROP TABLE IF EXISTS users" , 
             [],
@@ -155,10 +394,22 @@ fn flush_user_table(conn: &Connection, flush: bool) -> Result<()>{
             "DROP TABLE IF EXISTS contests" , 
             [],
         )?;
+        conn.execute(
+            "DROP TABLE IF EXISTS job_cases" , 
+            [],
+        )?;
         conn.execute(
             "DROP TABLE IF EXISTS jobs" , 
             [],
         )?;
+        conn.execute(
+            "DROP TABLE IF EXISTS schema_version" , 
+            [],
+        )?;
+        conn.execute(
+            "DROP TABLE IF EXISTS problems" , 
+            [],
+        )?;
     }
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,383 @@
+use rusqlite::{params, Connection, OptionalExtension, Result, Transaction};
+
+use crate::timestamp;
+
+/// a change of the schema, applied once and in order
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    apply: fn(&Transaction) -> Result<()>
+}
+
+/// append new steps at the end, never change applied ones
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create users, contests, jobs and problems",
+        apply: create_tables
+    },
+    Migration {
+        version: 2,
+        description: "move job cases into job_cases",
+        apply: create_job_cases
+    },
+    Migration {
+        version: 3,
+        description: "move contest problems and users into join tables",
+        apply: create_contest_members
+    },
+    Migration {
+        version: 4,
+        description: "index jobs by the columns they are filtered on",
+        apply: create_job_indexes
+    },
+    Migration {
+        version: 5,
+        description: "version jobs for state transitions",
+        apply: add_job_version
+    },
+    Migration {
+        version: 6,
+        description: "reference users from contest_users",
+        apply: reference_contest_users
+    },
+    Migration {
+        version: 7,
+        description: "never reuse the ids of deleted rows",
+        apply: autoincrement_ids
+    },
+    Migration {
+        version: 8,
+        description: "remember which problems come from the config",
+        apply: add_problem_from_config
+    }
+];
+
+/// databases created before migrations already have these tables
+fn create_tables(tx: &Transaction) -> Result<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS users (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL
+        )",
+        [],
+    )?;
+    // problem_ids and user_ids need vec to test
+    // when read, we need vec to test
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS contests (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            'from' TEXT NOT NULL,
+            'to' TEXT NOT NULL,
+            problem_ids TEXT NOT NULL,
+            user_ids TEXT NOT NULL,
+            submission_limit INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    // case need vec to test
+    // when read, we need vec to test
+    // sunmission divide into 5 parts
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS jobs (
+            id INTEGER PRIMARY KEY,
+            created_time TEXT NOT NULL,
+            updated_time TEXT NOT NULL,
+            source_code TEXT NOT NULL,
+            language TEXT NOT NULL,
+            user_id INTEGER NOT NULL,
+            contest_id INTEGER NOT NULL,
+            problem_id INTEGER NOT NULL,
+            state TEXT NOT NULL,
+            result TEXT NOT NULL,
+            score REAL NOT NULL,
+            cases TEXT NOT NULL
+        )",
+        [],
+    )?;
+    // misc and cases are json
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS problems (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            type TEXT NOT NULL,
+            misc TEXT NOT NULL,
+            cases TEXT NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// cases used to be a text column of jobs,
+/// `id:result:time:memory:info[:stderr]` joined by commas
+fn create_job_cases(tx: &Transaction) -> Result<()> {
+    tx.execute(
+        "CREATE TABLE job_cases (
+            job_id INTEGER NOT NULL,
+            id INTEGER NOT NULL,
+            result TEXT NOT NULL,
+            time INTEGER NOT NULL,
+            memory INTEGER NOT NULL,
+            info TEXT NOT NULL,
+            stderr TEXT,
+            PRIMARY KEY (job_id, id)
+        )",
+        [],
+    )?;
+    let rows: Vec<(usize, String)> = {
+        let mut stmt = tx.prepare("SELECT id, cases FROM jobs")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.collect::<Result<_>>()?
+    };
+    let mut stmt = tx.prepare(
+        "INSERT INTO job_cases (job_id, id, result, time, memory, info, stderr)
+        VALUES (?, ?, ?, ?, ?, ?, ?)"
+    )?;
+    for (job_id, cases) in rows {
+        for case in cases.split(',').filter(|x| !x.is_empty()) {
+            let v: Vec<&str> = case.split(':').collect();
+            if v.len() < 5 {
+                log::warn!("Skipped malformed case {:?} of job {}", case, job_id);
+                continue;
+            }
+            stmt.execute(params![
+                job_id,
+                v[0].parse::<usize>().unwrap_or(0),
+                v[1],
+                v[2].parse::<u64>().unwrap_or(0),
+                v[3].parse::<u64>().unwrap_or(0),
+                unescape_field(v[4]),
+                // missing in jobs saved before stderr was captured
+                v.get(5).filter(|x| !x.is_empty()).map(|x| unescape_field(x))
+            ])?;
+        }
+    }
+    // DROP COLUMN needs SQLite 3.35, which is why it is bundled
+    tx.execute("ALTER TABLE jobs DROP COLUMN cases", [])?;
+    Ok(())
+}
+
+/// undo the escaping of separators in the old cases column
+fn unescape_field(s: &str) -> String {
+    s.replace("%2C", ",").replace("%3A", ":").replace("%25", "%")
+}
+
+/// problem_ids and user_ids used to be text columns of contests,
+/// ids joined by commas
+fn create_contest_members(tx: &Transaction) -> Result<()> {
+    // problems and users may be loaded after the migration,
+    // so only the contest is a foreign key
+    for (table, column) in [("contest_problems", "problem_id"), ("contest_users", "user_id")] {
+        tx.execute(
+            &format!(
+                "CREATE TABLE {} (
+                    contest_id INTEGER NOT NULL REFERENCES contests(id) ON DELETE CASCADE,
+                    position INTEGER NOT NULL,
+                    {} INTEGER NOT NULL,
+                    PRIMARY KEY (contest_id, position)
+                )",
+                table, column
+            ),
+            [],
+        )?;
+    }
+    let rows: Vec<(usize, String, String)> = {
+        let mut stmt = tx.prepare("SELECT id, problem_ids, user_ids FROM contests")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+        rows.collect::<Result<_>>()?
+    };
+    for (contest_id, problem_ids, user_ids) in rows {
+        for (table, column, ids) in [
+            ("contest_problems", "problem_id", problem_ids),
+            ("contest_users", "user_id", user_ids)
+        ] {
+            let ids = ids.split(',').filter_map(|x| x.trim().parse::<usize>().ok());
+            for (position, id) in ids.enumerate() {
+                tx.execute(
+                    &format!("INSERT INTO {} (contest_id, position, {}) VALUES (?, ?, ?)", table, column),
+                    params![contest_id, position, id],
+                )?;
+            }
+        }
+    }
+    tx.execute("ALTER TABLE contests DROP COLUMN problem_ids", [])?;
+    tx.execute("ALTER TABLE contests DROP COLUMN user_ids", [])?;
+    Ok(())
+}
+
+fn create_job_indexes(tx: &Transaction) -> Result<()> {
+    for column in ["user_id", "contest_id", "problem_id", "state", "result", "created_time"] {
+        tx.execute(
+            &format!("CREATE INDEX IF NOT EXISTS jobs_{} ON jobs ({})", column, column),
+            [],
+        )?;
+    }
+    tx.execute("CREATE INDEX IF NOT EXISTS users_name ON users (name)", [])?;
+    Ok(())
+}
+
+fn add_job_version(tx: &Transaction) -> Result<()> {
+    tx.execute("ALTER TABLE jobs ADD COLUMN version INTEGER NOT NULL DEFAULT 0", [])?;
+    Ok(())
+}
+
+/// users in a contest cannot be deleted; problems of databases from
+/// before the problem API are only in the config until the server
+/// starts, so `problem::delete_problem` checks contest_problems instead
+fn reference_contest_users(tx: &Transaction) -> Result<()> {
+    // members are never dropped, a missing user stops the migration
+    let missing: Option<(usize, usize)> = tx.query_row(
+        "SELECT contest_id, user_id FROM contest_users
+         WHERE user_id NOT IN (SELECT id FROM users) LIMIT 1",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?))
+    ).optional()?;
+    if let Some((contest_id, user_id)) = missing {
+        return Err(rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_CONSTRAINT_FOREIGNKEY),
+            Some(format!(
+                "Contest {} lists user {}, which does not exist; remove it from the contest and migrate again",
+                contest_id, user_id
+            ))
+        ));
+    }
+    tx.execute(
+        "CREATE TABLE contest_users_new (
+            contest_id INTEGER NOT NULL REFERENCES contests(id) ON DELETE CASCADE,
+            position INTEGER NOT NULL,
+            user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE RESTRICT,
+            PRIMARY KEY (contest_id, position)
+        )",
+        [],
+    )?;
+    tx.execute("INSERT INTO contest_users_new SELECT contest_id, position, user_id FROM contest_users", [])?;
+    tx.execute("DROP TABLE contest_users", [])?;
+    tx.execute("ALTER TABLE contest_users_new RENAME TO contest_users", [])?;
+    Ok(())
+}
+
+/// the columns after `id` of the tables whose ids are allocated
+const ID_TABLES: [(&str, &str, usize); 4] = [
+    ("users", "name TEXT NOT NULL", 0),
+    ("problems", "name TEXT NOT NULL,
+        type TEXT NOT NULL,
+        misc TEXT NOT NULL,
+        cases TEXT NOT NULL", 0),
+    ("contests", "name TEXT NOT NULL,
+        'from' TEXT NOT NULL,
+        'to' TEXT NOT NULL,
+        submission_limit INTEGER NOT NULL", 1),
+    ("jobs", "created_time TEXT NOT NULL,
+        updated_time TEXT NOT NULL,
+        source_code TEXT NOT NULL,
+        language TEXT NOT NULL,
+        user_id INTEGER NOT NULL,
+        contest_id INTEGER NOT NULL,
+        problem_id INTEGER NOT NULL,
+        state TEXT NOT NULL,
+        result TEXT NOT NULL,
+        score REAL NOT NULL,
+        version INTEGER NOT NULL DEFAULT 0", 0)
+];
+
+/// the id an insert into a table of `ID_TABLES` takes, AUTOINCREMENT
+/// alone would start from 1 instead of the seeded first id
+pub fn next_id_sql(table: &str) -> String {
+    format!("(SELECT seq + 1 FROM sqlite_sequence WHERE name = '{}')", table)
+}
+
+/// AUTOINCREMENT keeps the largest id ever used in sqlite_sequence,
+/// which is seeded so that the first id stays the same
+fn autoincrement_ids(tx: &Transaction) -> Result<()> {
+    for (table, columns, first) in ID_TABLES {
+        tx.execute(
+            &format!("CREATE TABLE {}_new (id INTEGER PRIMARY KEY AUTOINCREMENT, {})", table, columns),
+            [],
+        )?;
+        tx.execute(&format!("INSERT INTO {}_new SELECT * FROM {}", table, table), [])?;
+        tx.execute(&format!("DROP TABLE {}", table), [])?;
+        tx.execute(&format!("ALTER TABLE {}_new RENAME TO {}", table, table), [])?;
+        // the copy may have left a sequence of 0 for an empty table
+        tx.execute("DELETE FROM sqlite_sequence WHERE name = ?", params![table])?;
+        tx.execute(
+            &format!(
+                "INSERT INTO sqlite_sequence (name, seq) SELECT ?, COALESCE(MAX(id), ?) FROM {}",
+                table
+            ),
+            params![table, first as i64 - 1],
+        )?;
+    }
+    // dropped with the tables
+    create_job_indexes(tx)
+}
+
+/// the stored problems used to be replaced by those of the config
+fn add_problem_from_config(tx: &Transaction) -> Result<()> {
+    tx.execute("ALTER TABLE problems ADD COLUMN from_config INTEGER NOT NULL DEFAULT 1", [])?;
+    Ok(())
+}
+
+fn create_version_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            description TEXT NOT NULL,
+            applied_time TEXT NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// 0 for a new database
+pub fn current_version(conn: &Connection) -> Result<u32> {
+    create_version_table(conn)?;
+    conn.query_row(
+        "SELECT COALESCE(MAX(version), 0) FROM schema_version",
+        [],
+        |row| row.get(0)
+    )
+}
+
+/// the version this server expects
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map(|x| x.version).unwrap_or(0)
+}
+
+pub fn pending(conn: &Connection) -> Result<Vec<&'static Migration>> {
+    let version = current_version(conn)?;
+    Ok(MIGRATIONS.iter().filter(|x| x.version > version).collect())
+}
+
+/// apply the pending migrations, each in its own transaction,
+/// return the applied ones
+pub fn migrate(conn: &mut Connection) -> Result<Vec<&'static Migration>> {
+    let migrations = pending(conn)?;
+    // tables are rebuilt by dropping and renaming them, which foreign keys
+    // would block, so they are checked before each commit instead
+    conn.execute_batch("PRAGMA foreign_keys = OFF")?;
+    for migration in &migrations {
+        let tx = conn.transaction()?;
+        (migration.apply)(&tx)?;
+        if tx.query_row("PRAGMA foreign_key_check", [], |_| Ok(())).optional()?.is_some() {
+            return Err(rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_CONSTRAINT_FOREIGNKEY),
+                Some(format!("Migration {} broke a foreign key", migration.version))
+            ));
+        }
+        tx.execute(
+            "INSERT INTO schema_version (version, description, applied_time) VALUES (?, ?, ?)",
+            params![
+                migration.version,
+                migration.description,
+                timestamp::format(&timestamp::now())
+            ],
+        )?;
+        tx.commit()?;
+        log::info!("Applied migration {}: {}", migration.version, migration.description);
+    }
+    conn.execute_batch("PRAGMA foreign_keys = ON")?;
+    Ok(migrations)
+}
@@ -0,0 +1,387 @@
+use serde::Deserialize;
+use zip::{ZipArchive, result::ZipError};
+use std::{
+    fs,
+    io::{Cursor, Read},
+    path::{Path, PathBuf},
+    process::Command
+};
+
+use crate::{
+    config::{self, Judge, Language, Misc, Problem},
+    polygon,
+    storage::Storage
+};
+
+const MANIFEST_NAMES: [&str; 4] = ["problem.json", "problem.yaml", "problem.yml", "problem.xml"];
+
+/// problem.json or problem.yaml of a package
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    /// the next free id when missing, must not be taken
+    id: Option<usize>,
+    name: String,
+    /// "standard" when missing, "spj" when there is a checker
+    #[serde(rename = "type")]
+    ty: Option<String>,
+    /// The unit is us, used by all cases
+    time_limit: u64,
+    #[serde(default)]
+    memory_limit: u64,
+    #[serde(default)]
+    output_limit: u64,
+    /// total score split evenly over the cases, 100 when missing
+    score: Option<f64>,
+    /// path of the checker source in the package, it is compiled and run
+    /// with the output and the answer file as arguments
+    checker: Option<String>,
+    /// the language the checker is compiled with, the one whose file name
+    /// has the extension of the checker when missing
+    checker_language: Option<String>,
+    /// the score of a subtask is split evenly over its cases,
+    /// cases outside all subtasks score nothing
+    subtasks: Option<Vec<Subtask>>,
+    misc: Option<Misc>
+}
+
+#[derive(Deserialize)]
+struct Subtask {
+    score: f64,
+    /// case ids starting from 1
+    cases: Vec<usize>
+}
+
+/// a problem package, either a directory or a zip archive,
+/// Polygon packages are recognized by their problem.xml
+pub enum Package {
+    Dir(PathBuf),
+    Zip {
+        archive: ZipArchive<Cursor<Vec<u8>>>,
+        /// directory of the manifest inside the archive
+        prefix: String,
+        /// bytes that may still be unpacked
+        unpacked_left: u64
+    }
+}
+
+impl Package {
+    /// a path ending with .zip is read as an archive
+    pub fn open(path: &str, judge: &Judge) -> Result<Package, String> {
+        if path.ends_with(".zip") {
+            let bytes = fs::read(path).map_err(|err| format!("Failed to read {}: {}", path, err))?;
+            Package::from_zip(bytes, judge)
+        } else {
+            Ok(Package::Dir(PathBuf::from(path)))
+        }
+    }
+
+    /// archives over the file count or unpacked size limits of `judge` are refused
+    pub fn from_zip(bytes: Vec<u8>, judge: &Judge) -> Result<Package, String> {
+        let mut archive = ZipArchive::new(Cursor::new(bytes))
+            .map_err(|err| format!("Invalid zip archive: {}", err))?;
+        if archive.len() > judge.max_package_files() {
+            return Err(format!("The package has more than {} files.", judge.max_package_files()));
+        }
+        // the sizes in the archive may lie, `read` checks them again
+        let mut size: u64 = 0;
+        for index in 0..archive.len() {
+            let file = archive.by_index_raw(index)
+                .map_err(|err| format!("Invalid zip archive: {}", err))?;
+            size = size.saturating_add(file.size());
+        }
+        if size > judge.max_unpacked_size() {
+            return Err(format!("The package unpacks to more than {} bytes.", judge.max_unpacked_size()));
+        }
+        // the package may be wrapped in a top level directory
+        let prefix = archive.file_names()
+            .filter_map(|name| {
+                let (dir, file) = name.rsplit_once('/').unwrap_or(("", name));
+                MANIFEST_NAMES.contains(&file).then_some(dir)
+            })
+            .min_by_key(|dir| dir.len())
+            .map(|dir| if dir.is_empty() { String::new() } else { dir.to_string() + "/" })
+            .ok_or("Missing problem.json, problem.yaml or problem.xml.".to_string())?;
+        Ok(Package::Zip { archive, prefix, unpacked_left: judge.max_unpacked_size() })
+    }
+
+    /// read a file of the package, None if it does not exist
+    pub fn read(&mut self, name: &str) -> Result<Option<Vec<u8>>, String> {
+        match self {
+            Package::Dir(dir) => match fs::read(dir.join(name)) {
+                Ok(bytes) => Ok(Some(bytes)),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(err) => Err(format!("Failed to read {}: {}", name, err))
+            },
+            Package::Zip { archive, prefix, unpacked_left } => {
+                let file = match archive.by_name(&(prefix.clone() + name)) {
+                    Ok(file) => file,
+                    Err(ZipError::FileNotFound) => return Ok(None),
+                    Err(err) => return Err(format!("Failed to read {}: {}", name, err))
+                };
+                let mut bytes = Vec::new();
+                file.take(*unpacked_left + 1).read_to_end(&mut bytes)
+                    .map_err(|err| format!("Failed to read {}: {}", name, err))?;
+                if bytes.len() as u64 > *unpacked_left {
+                    return Err("The package unpacks to more bytes than allowed.".to_string());
+                }
+                *unpacked_left -= bytes.len() as u64;
+                Ok(Some(bytes))
+            }
+        }
+    }
+
+    fn manifest(&mut self) -> Result<Option<Manifest>, String> {
+        if let Some(bytes) = self.read("problem.json")? {
+            return serde_json::from_slice(&bytes)
+                .map(Some)
+                .map_err(|err| format!("Invalid problem.json: {}", err));
+        }
+        for name in ["problem.yaml", "problem.yml"] {
+            if let Some(bytes) = self.read(name)? {
+                return serde_yaml::from_slice(&bytes)
+                    .map(Some)
+                    .map_err(|err| format!("Invalid {}: {}", name, err));
+            }
+        }
+        Ok(None)
+    }
+
+    /// copy the cases and the checker under `data_dir` and build the problem,
+    /// a new id is taken from `storage` when the manifest has none
+    pub fn load(&mut self, data_dir: &str, storage: &dyn Storage, languages: &[Language]) -> Result<Problem, String> {
+        let manifest = match self.manifest()? {
+            Some(manifest) => manifest,
+            None => {
+                let xml = self.read("problem.xml")?
+                    .ok_or("Missing problem.json, problem.yaml or problem.xml.".to_string())?;
+                let xml = String::from_utf8_lossy(&xml).to_string();
+                return polygon::load(self, &xml, data_dir, storage, languages);
+            }
+        };
+
+        // tests/1.in, tests/1.ans, tests/2.in ...
+        let mut tests = Vec::new();
+        loop {
+            let case_id = tests.len() + 1;
+            let input = match self.read(&format!("tests/{}.in", case_id))? {
+                Some(input) => input,
+                None => break
+            };
+            let answer = self.read(&format!("tests/{}.ans", case_id))?
+                .ok_or(format!("Missing tests/{}.ans.", case_id))?;
+            tests.push((input, answer));
+        }
+        if tests.is_empty() {
+            return Err("Missing tests/1.in.".to_string());
+        }
+
+        let mut scores = vec![0.0; tests.len()];
+        let mut misc = manifest.misc.unwrap_or_default();
+        match &manifest.subtasks {
+            Some(subtasks) => {
+                let mut grouped = vec![false; tests.len()];
+                for subtask in subtasks {
+                    for case_id in &subtask.cases {
+                        if *case_id < 1 || *case_id > tests.len() {
+                            return Err(format!("Subtask refers to missing case {}.", case_id));
+                        }
+                        if grouped[case_id - 1] {
+                            return Err(format!("Case {} is in more than one subtask.", case_id));
+                        }
+                        grouped[case_id - 1] = true;
+                        scores[case_id - 1] = subtask.score / subtask.cases.len() as f64;
+                    }
+                }
+                misc.packing = Some(subtasks.iter().map(|x| x.cases.clone()).collect());
+            }
+            None => {
+                let score = manifest.score.unwrap_or(100.0) / tests.len() as f64;
+                scores.fill(score);
+            }
+        }
+        // checkers run on the server, so they only come from the config
+        // or are compiled like submissions
+        if misc.special_judge.is_some() {
+            return Err("Special judges can only be set in the config.".to_string());
+        }
+
+        let checker = match &manifest.checker {
+            Some(checker) => {
+                let language = checker_language(checker, manifest.checker_language.as_deref(), languages)?;
+                let source = self.read(checker)?
+                    .ok_or(format!("Missing checker {}.", checker))?;
+                Some((language, source))
+            }
+            None => None
+        };
+
+        let id = match manifest.id {
+            Some(id) => {
+                if storage.problem(id).is_ok() {
+                    return Err(format!("Problem {} already exists.", id));
+                }
+                id
+            }
+            None => storage.next_problem_id().map_err(|err| err.to_string())?
+        };
+
+        // the package is valid, write the problem next to where it goes
+        let staging = Staging::new(data_dir, id)?;
+        let mut cases = Vec::new();
+        for (index, (input, answer)) in tests.iter().enumerate() {
+            cases.push(config::Case {
+                score: scores[index],
+                input_file: staging.save(&format!("{}.in", index + 1), input)?,
+                answer_file: staging.save(&format!("{}.ans", index + 1), answer)?,
+                time_limit: manifest.time_limit,
+                memory_limit: manifest.memory_limit,
+                output_limit: manifest.output_limit
+            });
+        }
+
+        let mut ty = manifest.ty.unwrap_or("standard".to_string());
+        if let Some((language, source)) = checker {
+            let checker_path = staging.compile_checker(language, &source, &[])?;
+            misc.special_judge = Some(vec![
+                checker_path,
+                "%OUTPUT%".to_string(),
+                "%ANSWER%".to_string()
+            ]);
+            ty = "spj".to_string();
+        }
+
+        let mut problem = Problem {
+            id,
+            name: manifest.name,
+            ty,
+            misc: Some(misc),
+            cases
+        };
+        problem.check()?;
+        staging.finish(&mut problem)?;
+        Ok(problem)
+    }
+}
+
+/// the language named by the manifest, or the one whose file name
+/// has the extension of the checker
+pub fn checker_language<'a>(
+    checker: &str,
+    name: Option<&str>,
+    languages: &'a [Language]
+) -> Result<&'a Language, String> {
+    match name {
+        Some(name) => languages.iter()
+            .find(|x| x.name == name)
+            .ok_or(format!("Unknown checker language {}.", name)),
+        None => {
+            let extension = Path::new(checker).extension();
+            languages.iter()
+                .find(|x| extension.is_some() && Path::new(&x.file_name).extension() == extension)
+                .ok_or(format!("No language compiles the checker {}.", checker))
+        }
+    }
+}
+
+/// the directory of a problem, written beside `data_dir/<id>` and
+/// swapped into place by `finish`, removed when dropped before that
+pub struct Staging {
+    dir: PathBuf,
+    target: PathBuf,
+    finished: bool
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        if !self.finished {
+            fs::remove_dir_all(&self.dir).ok();
+        }
+    }
+}
+
+impl Staging {
+    /// the files of problem `id`, if any, are only replaced by `finish`
+    pub fn new(data_dir: &str, id: usize) -> Result<Staging, String> {
+        let target = Path::new(data_dir).join(id.to_string());
+        let dir = Path::new(data_dir).join(format!(".{}-{}.import", id, std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir)
+            .map_err(|err| format!("Failed to create {}: {}", dir.display(), err))?;
+        Ok(Staging { dir, target, finished: false })
+    }
+
+    /// return the path of the staged file
+    pub fn save(&self, name: &str, bytes: &[u8]) -> Result<String, String> {
+        save_file(&self.dir, name, bytes)
+    }
+
+    /// compile a checker the way submissions are compiled, with `files`
+    /// beside the source, return the path of the staged program
+    pub fn compile_checker(
+        &self,
+        language: &Language,
+        source: &[u8],
+        files: &[(&str, &[u8])]
+    ) -> Result<String, String> {
+        let checker_dir = self.dir.join("checker");
+        fs::create_dir_all(&checker_dir)
+            .map_err(|err| format!("Failed to create {}: {}", checker_dir.display(), err))?;
+        let source_path = save_file(&checker_dir, &language.file_name, source)?;
+        for (name, bytes) in files {
+            save_file(&checker_dir, name, bytes)?;
+        }
+        let program_path = self.dir.join("check").to_string_lossy().to_string();
+        let args: Vec<String> = language.command.iter()
+            .map(|arg| arg.replace("%INPUT%", &source_path).replace("%OUTPUT%", &program_path))
+            .collect();
+        let status = Command::new(&args[0])
+            .args(&args[1..])
+            .status()
+            .map_err(|err| format!("Failed to compile the checker: {}", err))?;
+        if !status.success() {
+            return Err("Failed to compile the checker.".to_string());
+        }
+        Ok(program_path)
+    }
+
+    /// move the problem into place, over the files of the problem it
+    /// replaces or of a deleted one, and point `problem` at them
+    pub fn finish(mut self, problem: &mut Problem) -> Result<(), String> {
+        // the old files are renamed as a whole, never rewritten
+        // under the jobs still judging with them
+        let old = self.dir.with_extension("old");
+        fs::remove_dir_all(&old).ok();
+        let replaced = fs::rename(&self.target, &old).is_ok();
+        if let Err(err) = fs::rename(&self.dir, &self.target) {
+            if replaced {
+                fs::rename(&old, &self.target).ok();
+            }
+            return Err(format!("Failed to create {}: {}", self.target.display(), err));
+        }
+        self.finished = true;
+        if replaced {
+            fs::remove_dir_all(&old).ok();
+        }
+
+        let moved = |path: &mut String| {
+            if let Ok(rest) = Path::new(path.as_str()).strip_prefix(&self.dir) {
+                *path = self.target.join(rest).to_string_lossy().to_string();
+            }
+        };
+        for case in &mut problem.cases {
+            moved(&mut case.input_file);
+            moved(&mut case.answer_file);
+        }
+        if let Some(command) = problem.misc.as_mut().and_then(|x| x.special_judge.as_mut()) {
+            command.iter_mut().for_each(moved);
+        }
+        Ok(())
+    }
+}
+
+pub fn save_file(dir: &Path, name: &str, bytes: &[u8]) -> Result<String, String> {
+    let path = dir.join(name);
+    fs::write(&path, bytes).map_err(|err| format!("Failed to write {}: {}", path.display(), err))?;
+    Ok(path.to_string_lossy().to_string())
+}
@@ -0,0 +1,213 @@
+use roxmltree::{Document, Node};
+use std::collections::HashMap;
+
+use crate::{
+    config::{self, Language, Misc, Problem},
+    package::{checker_language, Package, Staging},
+    storage::Storage
+};
+
+/// standard checkers that compare lines the way "standard" problems do,
+/// the others are compiled from their source like custom checkers
+const STANDARD_CHECKERS: [&str; 1] = ["std::lcmp.cpp"];
+
+/// a test of the main testset
+struct Test {
+    points: Option<f64>,
+    group: Option<String>
+}
+
+/// a group of tests, scored as a whole with points-policy "complete-group"
+struct Group {
+    points: Option<f64>,
+    complete: bool
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|x| x.has_tag_name(name))
+}
+
+fn child_text(node: Node, name: &str) -> Option<String> {
+    child(node, name).and_then(|x| x.text()).map(|x| x.trim().to_string())
+}
+
+fn parse_number<T: std::str::FromStr>(text: Option<String>, name: &str) -> Result<T, String> {
+    text.ok_or(format!("Missing {} in problem.xml.", name))?
+        .parse()
+        .map_err(|_| format!("Invalid {} in problem.xml.", name))
+}
+
+/// expand printf patterns such as `tests/%02d`
+fn format_pattern(pattern: &str, number: usize) -> String {
+    let start = match pattern.find('%') {
+        Some(start) => start,
+        None => return pattern.to_string()
+    };
+    let rest = &pattern[start + 1..];
+    let end = match rest.find('d') {
+        Some(end) => end,
+        None => return pattern.to_string()
+    };
+    let width: usize = rest[..end].parse().unwrap_or(0);
+    let number = if rest.starts_with('0') {
+        format!("{:0width$}", number, width = width)
+    } else {
+        format!("{:width$}", number, width = width)
+    };
+    pattern[..start].to_string() + &number + &rest[end + 1..]
+}
+
+/// build a problem from the problem.xml of a Polygon package, checkers
+/// other than lcmp are compiled with the language of their extension
+pub fn load(
+    package: &mut Package,
+    xml: &str,
+    data_dir: &str,
+    storage: &dyn Storage,
+    languages: &[Language]
+) -> Result<Problem, String> {
+    let document = Document::parse(xml).map_err(|err| format!("Invalid problem.xml: {}", err))?;
+    let root = document.root_element();
+    let names: Vec<Node> = child(root, "names")
+        .map(|x| x.children().filter(|x| x.has_tag_name("name")).collect())
+        .unwrap_or_default();
+    let name = names.iter()
+        .find(|x| x.attribute("language") == Some("english"))
+        .or(names.first())
+        .and_then(|x| x.attribute("value"))
+        .or(root.attribute("short-name"))
+        .unwrap_or("")
+        .to_string();
+
+    let testset = child(root, "judging")
+        .and_then(|judging| {
+            judging.children()
+                .find(|x| x.has_tag_name("testset") && x.attribute("name") == Some("tests"))
+        })
+        .ok_or("Missing the tests testset in problem.xml.".to_string())?;
+    // milliseconds and bytes
+    let time_limit: u64 = parse_number(child_text(testset, "time-limit"), "time-limit")?;
+    let memory_limit: u64 = parse_number(child_text(testset, "memory-limit"), "memory-limit")?;
+    let input_pattern = child_text(testset, "input-path-pattern")
+        .ok_or("Missing input-path-pattern in problem.xml.".to_string())?;
+    let answer_pattern = child_text(testset, "answer-path-pattern")
+        .ok_or("Missing answer-path-pattern in problem.xml.".to_string())?;
+
+    let mut tests = Vec::new();
+    if let Some(node) = child(testset, "tests") {
+        for test in node.children().filter(|x| x.has_tag_name("test")) {
+            tests.push(Test {
+                points: test.attribute("points").and_then(|x| x.parse().ok()),
+                group: test.attribute("group").map(|x| x.to_string())
+            });
+        }
+    }
+    if tests.is_empty() {
+        return Err("No tests in problem.xml.".to_string());
+    }
+    let mut groups = HashMap::new();
+    if let Some(node) = child(testset, "groups") {
+        for group in node.children().filter(|x| x.has_tag_name("group")) {
+            groups.insert(group.attribute("name").unwrap_or("").to_string(), Group {
+                points: group.attribute("points").and_then(|x| x.parse().ok()),
+                complete: group.attribute("points-policy") == Some("complete-group")
+            });
+        }
+    }
+
+    // read everything before touching the data directory
+    let mut files = Vec::new();
+    for index in 0..tests.len() {
+        let input_path = format_pattern(&input_pattern, index + 1);
+        let answer_path = format_pattern(&answer_pattern, index + 1);
+        let input = package.read(&input_path)?.ok_or(format!("Missing {}.", input_path))?;
+        let answer = package.read(&answer_path)?.ok_or(format!("Missing {}.", answer_path))?;
+        files.push((input, answer));
+    }
+
+    // tests of complete groups share the points of their group
+    let mut scores: Vec<f64> = tests.iter().map(|x| x.points.unwrap_or(0.0)).collect();
+    let mut packing: Vec<Vec<usize>> = Vec::new();
+    let mut group_names: Vec<&String> = groups.keys().collect();
+    group_names.sort();
+    for group_name in group_names {
+        let group = &groups[group_name];
+        if !group.complete {
+            continue;
+        }
+        let members: Vec<usize> = (0..tests.len())
+            .filter(|x| tests[*x].group.as_ref() == Some(group_name))
+            .collect();
+        if members.is_empty() {
+            continue;
+        }
+        let points = group.points
+            .unwrap_or(members.iter().map(|x| scores[*x]).sum());
+        for index in &members {
+            scores[*index] = points / members.len() as f64;
+        }
+        packing.push(members.iter().map(|x| x + 1).collect());
+    }
+    if scores.iter().all(|x| *x == 0.0) {
+        scores.fill(100.0 / tests.len() as f64);
+    }
+
+    let checker = child(root, "assets").and_then(|x| child(x, "checker"));
+    let checker_name = checker.and_then(|x| x.attribute("name")).unwrap_or("");
+    let checker_source = match checker {
+        Some(checker) if !STANDARD_CHECKERS.contains(&checker_name) => {
+            let path = child(checker, "source")
+                .and_then(|x| x.attribute("path"))
+                .ok_or("Missing checker source in problem.xml.".to_string())?;
+            let language = checker_language(path, None, languages)?;
+            let source = package.read(path)?.ok_or(format!("Missing {}.", path))?;
+            // checkers include testlib.h from the same directory
+            let testlib = package.read("files/testlib.h")?
+                .ok_or("Missing files/testlib.h.".to_string())?;
+            Some((language, source, testlib))
+        }
+        _ => None
+    };
+
+    let id = storage.next_problem_id().map_err(|err| err.to_string())?;
+    let staging = Staging::new(data_dir, id)?;
+    let mut cases = Vec::new();
+    for (index, (input, answer)) in files.iter().enumerate() {
+        cases.push(config::Case {
+            score: scores[index],
+            input_file: staging.save(&format!("{}.in", index + 1), input)?,
+            answer_file: staging.save(&format!("{}.ans", index + 1), answer)?,
+            time_limit: time_limit * 1000,
+            memory_limit,
+            output_limit: 0
+        });
+    }
+
+    let mut misc = Misc::default();
+    if !packing.is_empty() {
+        misc.packing = Some(packing);
+    }
+    let mut ty = "standard".to_string();
+    if let Some((language, source, testlib)) = checker_source {
+        let checker_path = staging.compile_checker(language, &source, &[("testlib.h", &testlib)])?;
+        misc.special_judge = Some(vec![
+            checker_path,
+            "%INPUT%".to_string(),
+            "%OUTPUT%".to_string(),
+            "%ANSWER%".to_string()
+        ]);
+        misc.testlib_checker = Some(true);
+        ty = "spj".to_string();
+    }
+
+    let mut problem = Problem {
+        id,
+        name,
+        ty,
+        misc: Some(misc),
+        cases
+    };
+    problem.check()?;
+    staging.finish(&mut problem)?;
+    Ok(problem)
+}
@@ -0,0 +1,387 @@
+use actix_web::{HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use rusqlite::{params, Connection, Result, Row, Transaction, TransactionBehavior};
+use std::{
+    fs,
+    path::Path
+};
+
+use crate::{
+    post, get, put, delete,
+    web, Responder,
+    ErrorMessage, SharedConfig, invalid_argument,
+    config::{self, Misc, Problem},
+    package::{Package, Staging},
+    storage::{self, Storage, StorageError}
+};
+
+/// files are always written under the data directory,
+/// paths on the server are not accepted
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct PostCase {
+    score: f64,
+    /// content of the input file
+    input: String,
+    /// content of the answer file
+    answer: String,
+    time_limit: u64,
+    memory_limit: u64,
+    #[serde(default)]
+    output_limit: u64
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct PostProblem {
+    id: Option<usize>,
+    name: String,
+    #[serde(rename = "type")]
+    ty: String,
+    misc: Option<Misc>,
+    cases: Vec<PostCase>
+}
+
+impl PostProblem {
+    /// write the case contents and build the problem from the staged files
+    fn to_problem(&self, id: usize, staging: &Staging) -> Result<Problem, String> {
+        let mut cases = Vec::new();
+        for (index, case) in self.cases.iter().enumerate() {
+            cases.push(config::Case {
+                score: case.score,
+                input_file: staging.save(&format!("{}.in", index + 1), case.input.as_bytes())?,
+                answer_file: staging.save(&format!("{}.ans", index + 1), case.answer.as_bytes())?,
+                time_limit: case.time_limit,
+                memory_limit: case.memory_limit,
+                output_limit: case.output_limit
+            });
+        }
+        Ok(Problem {
+            id,
+            name: self.name.clone(),
+            ty: self.ty.clone(),
+            misc: self.misc.clone(),
+            cases
+        })
+    }
+
+    /// checkers run commands on the server, so they only come from the config
+    fn check_misc(&self) -> Option<String> {
+        let misc = self.misc.as_ref()?;
+        if misc.special_judge.is_some() {
+            return Some("Special judges can only be set in the config.".to_string());
+        }
+        None
+    }
+
+    /// save the body as problem `id`, the cases of a problem it replaces
+    /// are only removed once the new ones are valid
+    fn save(&self, id: usize, data_dir: &str, storage: &dyn Storage) -> HttpResponse {
+        let staging = match Staging::new(data_dir, id) {
+            Ok(staging) => staging,
+            Err(message) => return internal_error(message)
+        };
+        let mut problem = match self.to_problem(id, &staging) {
+            Ok(problem) => problem,
+            Err(message) => return internal_error(message)
+        };
+        if let Err(message) = problem.check() {
+            return invalid_argument(message);
+        }
+        if let Err(message) = staging.finish(&mut problem) {
+            return internal_error(message);
+        }
+        storage.save_problem(&problem).unwrap();
+        HttpResponse::Ok().json(problem)
+    }
+}
+
+fn forbidden() -> HttpResponse {
+    HttpResponse::Forbidden().json({
+        ErrorMessage{
+            code: (7),
+            reason: ("ERR_FORBIDDEN".to_string()),
+            message: ("Only admins can change problems.".to_string())
+        }
+    })
+}
+
+fn not_found(problem_id: usize) -> HttpResponse {
+    HttpResponse::NotFound().json({
+        ErrorMessage{
+            code: (3),
+            reason: ("ERR_NOT_FOUND".to_string()),
+            message: ("Problem ".to_string() + &problem_id.to_string() + " not found.")
+        }
+    })
+}
+
+fn internal_error(message: String) -> HttpResponse {
+    log::error!("Failed to save problem: {}", message);
+    HttpResponse::InternalServerError().json({
+        ErrorMessage{
+            code: (6),
+            reason: ("ERR_INTERNAL".to_string()),
+            message: ("Failed to save test cases.".to_string())
+        }
+    })
+}
+
+#[post("/problems")]
+#[allow(unreachable_code)]
+async fn post_problems(
+    http_request: HttpRequest,
+    body: web::Json<PostProblem>,
+    config: web::Data<SharedConfig>,
+    storage: web::Data<dyn Storage>
+) -> impl Responder {
+    let config = config.get();
+    log::info!("Post problem {}", body.name);
+    if !config.is_admin(&http_request) {
+        return forbidden();
+    }
+    if let Some(message) = body.check_misc() {
+        return invalid_argument(message);
+    }
+    let id = match body.id {
+        Some(id) => {
+            if storage.problem(id).is_ok() {
+                return invalid_argument("Problem ".to_string() + &id.to_string() + " already exists.");
+            }
+            id
+        }
+        None => storage.next_problem_id().unwrap()
+    };
+    body.save(id, &config.judge.data_dir(), storage.get_ref())
+}
+
+/// the body is a zip archive of a problem package
+#[post("/problems/import")]
+#[allow(unreachable_code)]
+async fn post_problems_import(
+    http_request: HttpRequest,
+    body: web::Bytes,
+    config: web::Data<SharedConfig>,
+    storage: web::Data<dyn Storage>
+) -> impl Responder {
+    let config = config.get();
+    log::info!("Import problem");
+    if !config.is_admin(&http_request) {
+        return forbidden();
+    }
+    // checkers are compiled
+    let package_storage = storage.clone();
+    let problem = web::block(move || {
+        Package::from_zip(body.to_vec(), &config.judge).and_then(|mut package| {
+            package.load(&config.judge.data_dir(), package_storage.get_ref(), &config.languages)
+        })
+    }).await.unwrap();
+    match problem {
+        Ok(problem) => {
+            storage.save_problem(&problem).unwrap();
+            HttpResponse::Ok().json(problem)
+        }
+        Err(message) => invalid_argument(message)
+    }
+}
+
+#[get("/problems")]
+#[allow(unreachable_code)]
+async fn get_problems(
+    storage: web::Data<dyn Storage>
+) -> impl Responder {
+    log::info!("Get problems");
+    let problem_list = storage.problems().unwrap();
+    HttpResponse::Ok().json(problem_list)
+}
+
+#[get("/problems/{problemId}")]
+#[allow(unreachable_code)]
+async fn get_problems_id(
+    problem_id: web::Path<usize>,
+    storage: web::Data<dyn Storage>
+) -> impl Responder {
+    log::info!("Get problems {}", problem_id);
+    match storage.problem(*problem_id) {
+        Ok(problem) => HttpResponse::Ok().json(problem),
+        Err(_) => not_found(*problem_id)
+    }
+}
+
+#[put("/problems/{problemId}")]
+#[allow(unreachable_code)]
+async fn put_problems(
+    http_request: HttpRequest,
+    problem_id: web::Path<usize>,
+    body: web::Json<PostProblem>,
+    config: web::Data<SharedConfig>,
+    storage: web::Data<dyn Storage>
+) -> impl Responder {
+    let config = config.get();
+    log::info!("Put problems {}", problem_id);
+    if !config.is_admin(&http_request) {
+        return forbidden();
+    }
+    if storage.problem(*problem_id).is_err() {
+        return not_found(*problem_id);
+    }
+    if let Some(message) = body.check_misc() {
+        return invalid_argument(message);
+    }
+    body.save(*problem_id, &config.judge.data_dir(), storage.get_ref())
+}
+
+#[delete("/problems/{problemId}")]
+#[allow(unreachable_code)]
+async fn delete_problems(
+    http_request: HttpRequest,
+    problem_id: web::Path<usize>,
+    config: web::Data<SharedConfig>,
+    storage: web::Data<dyn Storage>
+) -> impl Responder {
+    let config = config.get();
+    log::info!("Delete problems {}", problem_id);
+    if !config.is_admin(&http_request) {
+        return forbidden();
+    }
+    // the data is only removed once nothing refers to the problem
+    match storage.delete_problem(*problem_id) {
+        Ok(()) => (),
+        Err(StorageError::NotFound) => return not_found(*problem_id),
+        Err(StorageError::Invalid(message)) => return HttpResponse::BadRequest().json({
+            ErrorMessage{
+                code: (2),
+                reason: ("ERR_INVALID_STATE".to_string()),
+                message: (message)
+            }
+        }),
+        Err(err) => panic!("{}", err)
+    }
+    let problem_dir = Path::new(&config.judge.data_dir()).join(problem_id.to_string());
+    fs::remove_dir_all(problem_dir).ok();
+    HttpResponse::Ok().json(())
+}
+
+/// problems in config.json replace the stored ones with the same id,
+/// except those created or changed through the API
+pub fn init_problems(storage: &dyn Storage, problems: &[Problem]) -> storage::Result<()> {
+    for problem in problems {
+        if !storage.save_config_problem(problem)? {
+            log::warn!("Problem {} was changed through the API, kept it instead of the config", problem.id);
+        }
+    }
+    Ok(())
+}
+
+/// reserve an id no problem ever had, problems from the config
+/// may have taken ids past the sequence
+pub fn next_problem_id(conn: &Connection) -> Result<usize> {
+    let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+    tx.execute(
+        "UPDATE sqlite_sequence SET seq = MAX(seq, (SELECT COALESCE(MAX(id), -1) FROM problems)) + 1
+        WHERE name = 'problems'",
+        [],
+    )?;
+    let id = tx.query_row("SELECT seq FROM sqlite_sequence WHERE name = 'problems'", [], |row| row.get(0))?;
+    tx.commit()?;
+    Ok(id)
+}
+
+fn to_sql_error(err: serde_json::Error) -> rusqlite::Error {
+    rusqlite::Error::ToSqlConversionFailure(Box::new(err))
+}
+
+fn from_sql_error(index: usize, err: serde_json::Error) -> rusqlite::Error {
+    rusqlite::Error::FromSqlConversionFailure(index, rusqlite::types::Type::Text, Box::new(err))
+}
+
+/// misc and cases are stored as json
+fn read_problem(row: &Row) -> Result<Problem> {
+    let misc: String = row.get(3)?;
+    let cases: String = row.get(4)?;
+    Ok(Problem {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        ty: row.get(2)?,
+        misc: serde_json::from_str(&misc).map_err(|err| from_sql_error(3, err))?,
+        cases: serde_json::from_str(&cases).map_err(|err| from_sql_error(4, err))?
+    })
+}
+
+/// an update keeps the row, which contests and jobs refer to,
+/// the problem no longer belongs to the config
+pub fn save_problem(conn: &Connection, problem: &Problem) -> Result<()> {
+    conn.execute(
+        "INSERT INTO problems (id, name, type, misc, cases, from_config)
+        VALUES (?, ?, ?, ?, ?, 0)
+        ON CONFLICT (id) DO UPDATE SET
+            name = excluded.name, type = excluded.type, misc = excluded.misc, cases = excluded.cases,
+            from_config = 0",
+        params![
+            problem.id,
+            problem.name,
+            problem.ty,
+            serde_json::to_string(&problem.misc).map_err(to_sql_error)?,
+            serde_json::to_string(&problem.cases).map_err(to_sql_error)?
+        ],
+    )?;
+    Ok(())
+}
+
+/// save a problem of the config, false when the stored one was saved
+/// by `save_problem` and differs
+pub fn save_config_problem(conn: &Connection, problem: &Problem) -> Result<bool> {
+    let changed = conn.execute(
+        "INSERT INTO problems (id, name, type, misc, cases, from_config)
+        VALUES (?, ?, ?, ?, ?, 1)
+        ON CONFLICT (id) DO UPDATE SET
+            name = excluded.name, type = excluded.type, misc = excluded.misc, cases = excluded.cases,
+            from_config = 1
+        WHERE problems.from_config = 1
+            OR (problems.name, problems.type, problems.misc, problems.cases)
+                = (excluded.name, excluded.type, excluded.misc, excluded.cases)",
+        params![
+            problem.id,
+            problem.name,
+            problem.ty,
+            serde_json::to_string(&problem.misc).map_err(to_sql_error)?,
+            serde_json::to_string(&problem.cases).map_err(to_sql_error)?
+        ],
+    )?;
+    Ok(changed > 0)
+}
+
+/// delete a problem no job or contest refers to
+pub fn delete_problem(conn: &Connection, id: usize) -> storage::Result<()> {
+    let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+    // submissions keep referring to their problem
+    let jobs: usize = tx.query_row("SELECT COUNT(*) FROM jobs WHERE problem_id = ?", params![id], |row| row.get(0))?;
+    if jobs > 0 {
+        return Err(StorageError::Invalid("Problem ".to_string() + &id.to_string() + " has submissions."));
+    }
+    let contests: usize = tx.query_row(
+        "SELECT COUNT(*) FROM contest_problems WHERE problem_id = ?",
+        params![id],
+        |row| row.get(0)
+    )?;
+    if contests > 0 {
+        return Err(StorageError::Invalid("Problem ".to_string() + &id.to_string() + " is used by contests."));
+    }
+    if tx.execute("DELETE FROM problems WHERE id = ?", params![id])? == 0 {
+        return Err(StorageError::NotFound);
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// sorted by id
+pub fn query_problems(conn: &Connection) -> Result<Vec<Problem>> {
+    let mut stmt = conn.prepare("SELECT id, name, type, misc, cases FROM problems ORDER BY id")?;
+    let problems = stmt.query_map([], read_problem)?;
+    problems.collect()
+}
+
+pub fn query_problem(conn: &Connection, id: usize) -> Result<Problem> {
+    let mut stmt = conn.prepare("SELECT id, name, type, misc, cases FROM problems WHERE id = ?")?;
+    let mut problems = stmt.query_map(params![id], read_problem)?;
+    problems.next().unwrap_or(Err(rusqlite::Error::QueryReturnedNoRows))
+}
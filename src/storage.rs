@@ -0,0 +1,397 @@
+use r2d2::{Pool, PooledConnection};
+use r2d2_sqlite::SqliteConnectionManager;
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    sync::Mutex
+};
+
+use crate::{
+    config::Problem,
+    contest::{self, Contest},
+    job::{self, JobContent, QueryJob},
+    problem,
+    timestamp,
+    user::{self, User}
+};
+
+#[derive(Debug)]
+pub enum StorageError {
+    NotFound,
+    /// a query that cannot be run, such as an unknown order
+    Invalid(String),
+    Backend(String)
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StorageError::NotFound => write!(f, "Not found"),
+            StorageError::Invalid(message) => write!(f, "{}", message),
+            StorageError::Backend(message) => write!(f, "Storage error: {}", message)
+        }
+    }
+}
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(err: rusqlite::Error) -> StorageError {
+        match err {
+            rusqlite::Error::QueryReturnedNoRows => StorageError::NotFound,
+            err => StorageError::Backend(err.to_string())
+        }
+    }
+}
+
+impl From<r2d2::Error> for StorageError {
+    fn from(err: r2d2::Error) -> StorageError {
+        StorageError::Backend(err.to_string())
+    }
+}
+
+pub type Result<T> = std::result::Result<T, StorageError>;
+
+/// where users, problems, contests and jobs are kept,
+/// the ids of deleted rows are never handed out again
+pub trait Storage: Send + Sync {
+    fn users(&self) -> Result<Vec<User>>;
+    fn user(&self, id: usize) -> Result<User>;
+    /// allocate the id of the user and save it
+    fn insert_user(&self, user: &mut User) -> Result<()>;
+    fn update_user(&self, user: &User) -> Result<()>;
+
+    /// sorted by id
+    fn problems(&self) -> Result<Vec<Problem>>;
+    fn problem(&self, id: usize) -> Result<Problem>;
+    /// reserve an id no problem ever had
+    fn next_problem_id(&self) -> Result<usize>;
+    /// insert or replace the problem with the same id
+    fn save_problem(&self, problem: &Problem) -> Result<()>;
+    /// save a problem of the config, false when the one with its id was
+    /// saved by `save_problem` and differs, it is kept then
+    fn save_config_problem(&self, problem: &Problem) -> Result<bool>;
+    /// fails with Invalid while jobs or contests refer to the problem
+    fn delete_problem(&self, id: usize) -> Result<()>;
+
+    fn contests(&self) -> Result<Vec<Contest>>;
+    fn contest(&self, id: usize) -> Result<Contest>;
+    /// allocate the id of the contest and save it, ids start from 1
+    fn insert_contest(&self, contest: &mut Contest) -> Result<()>;
+    fn update_contest(&self, contest: &Contest) -> Result<()>;
+
+    /// jobs matching the filters of the query, in its order,
+    /// stderr is left out where `JobContent::hide_stderr` would remove it
+    fn jobs(&self, query: &QueryJob) -> Result<Vec<JobContent>>;
+    fn job(&self, id: usize) -> Result<JobContent>;
+    /// allocate the id of the job and save it
+    fn insert_job(&self, job: &mut JobContent) -> Result<()>;
+    /// save the state, result, score and the given cases of a job, all cases
+    /// when None, if it is still in state `from` at the version it was read;
+    /// false when someone else changed it first
+    fn transition_job(&self, job: &mut JobContent, from: &str, case_ids: Option<&[usize]>) -> Result<bool>;
+}
+
+/// the tables of a sqlite database
+pub struct SqliteStorage {
+    pool: Pool<SqliteConnectionManager>
+}
+
+impl SqliteStorage {
+    pub fn new(pool: Pool<SqliteConnectionManager>) -> SqliteStorage {
+        SqliteStorage { pool }
+    }
+
+    fn conn(&self) -> Result<PooledConnection<SqliteConnectionManager>> {
+        Ok(self.pool.get()?)
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn users(&self) -> Result<Vec<User>> {
+        Ok(user::query_users(&*self.conn()?)?)
+    }
+
+    fn user(&self, id: usize) -> Result<User> {
+        Ok(user::query_user(&*self.conn()?, id)?)
+    }
+
+    fn insert_user(&self, user: &mut User) -> Result<()> {
+        Ok(user::insert_user(&*self.conn()?, user)?)
+    }
+
+    fn update_user(&self, user: &User) -> Result<()> {
+        Ok(user::update_user(&*self.conn()?, user)?)
+    }
+
+    fn problems(&self) -> Result<Vec<Problem>> {
+        Ok(problem::query_problems(&*self.conn()?)?)
+    }
+
+    fn problem(&self, id: usize) -> Result<Problem> {
+        Ok(problem::query_problem(&*self.conn()?, id)?)
+    }
+
+    fn next_problem_id(&self) -> Result<usize> {
+        Ok(problem::next_problem_id(&*self.conn()?)?)
+    }
+
+    fn save_problem(&self, problem: &Problem) -> Result<()> {
+        Ok(problem::save_problem(&*self.conn()?, problem)?)
+    }
+
+    fn save_config_problem(&self, problem: &Problem) -> Result<bool> {
+        Ok(problem::save_config_problem(&*self.conn()?, problem)?)
+    }
+
+    fn delete_problem(&self, id: usize) -> Result<()> {
+        problem::delete_problem(&*self.conn()?, id)
+    }
+
+    fn contests(&self) -> Result<Vec<Contest>> {
+        Ok(contest::query_contests(&*self.conn()?)?)
+    }
+
+    fn contest(&self, id: usize) -> Result<Contest> {
+        Ok(contest::query_contest(&*self.conn()?, id)?)
+    }
+
+    fn insert_contest(&self, contest: &mut Contest) -> Result<()> {
+        Ok(contest::insert_contest(&*self.conn()?, contest)?)
+    }
+
+    fn update_contest(&self, contest: &Contest) -> Result<()> {
+        Ok(contest::update_contest(&*self.conn()?, contest)?)
+    }
+
+    fn jobs(&self, query: &QueryJob) -> Result<Vec<JobContent>> {
+        let (sql, values) = query.to_sql().map_err(StorageError::Invalid)?;
+        Ok(job::query_jobs_sql(&*self.conn()?, &sql, &values, query.admin)?)
+    }
+
+    fn job(&self, id: usize) -> Result<JobContent> {
+        Ok(job::query_job(&*self.conn()?, id)?)
+    }
+
+    fn insert_job(&self, job: &mut JobContent) -> Result<()> {
+        Ok(job::insert_job(&*self.conn()?, job)?)
+    }
+
+    fn transition_job(&self, job: &mut JobContent, from: &str, case_ids: Option<&[usize]>) -> Result<bool> {
+        Ok(job::transition_job(&*self.conn()?, job, from, case_ids)?)
+    }
+}
+
+struct Tables {
+    users: BTreeMap<usize, User>,
+    problems: BTreeMap<usize, Problem>,
+    /// ids of the problems saved from the config
+    config_problems: BTreeSet<usize>,
+    contests: BTreeMap<usize, Contest>,
+    jobs: BTreeMap<usize, JobContent>,
+    /// the next id of each table, they never go back
+    next_user_id: usize,
+    next_problem_id: usize,
+    next_contest_id: usize,
+    next_job_id: usize
+}
+
+impl Default for Tables {
+    fn default() -> Tables {
+        Tables {
+            users: BTreeMap::new(),
+            problems: BTreeMap::new(),
+            config_problems: BTreeSet::new(),
+            contests: BTreeMap::new(),
+            jobs: BTreeMap::new(),
+            next_user_id: 0,
+            next_problem_id: 0,
+            // contest 0 means no contest
+            next_contest_id: 1,
+            next_job_id: 0
+        }
+    }
+}
+
+/// everything in memory, lost when the server stops
+#[derive(Default)]
+pub struct MemoryStorage {
+    tables: Mutex<Tables>
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+}
+
+/// an id the table never had, like AUTOINCREMENT, rows saved with
+/// their own id may be past `next`
+fn allocate_id<T>(map: &BTreeMap<usize, T>, next: &mut usize) -> usize {
+    let id = map.keys().next_back().map_or(*next, |x| (x + 1).max(*next));
+    *next = id + 1;
+    id
+}
+
+impl Storage for MemoryStorage {
+    fn users(&self) -> Result<Vec<User>> {
+        Ok(self.tables.lock().unwrap().users.values().cloned().collect())
+    }
+
+    fn user(&self, id: usize) -> Result<User> {
+        self.tables.lock().unwrap().users.get(&id).cloned().ok_or(StorageError::NotFound)
+    }
+
+    fn insert_user(&self, user: &mut User) -> Result<()> {
+        let tables = &mut *self.tables.lock().unwrap();
+        user.id = allocate_id(&tables.users, &mut tables.next_user_id);
+        tables.users.insert(user.id, user.clone());
+        Ok(())
+    }
+
+    fn update_user(&self, user: &User) -> Result<()> {
+        let mut tables = self.tables.lock().unwrap();
+        match tables.users.get_mut(&user.id) {
+            Some(stored) => *stored = user.clone(),
+            None => return Err(StorageError::NotFound)
+        }
+        Ok(())
+    }
+
+    fn problems(&self) -> Result<Vec<Problem>> {
+        Ok(self.tables.lock().unwrap().problems.values().cloned().collect())
+    }
+
+    fn problem(&self, id: usize) -> Result<Problem> {
+        self.tables.lock().unwrap().problems.get(&id).cloned().ok_or(StorageError::NotFound)
+    }
+
+    fn next_problem_id(&self) -> Result<usize> {
+        let tables = &mut *self.tables.lock().unwrap();
+        Ok(allocate_id(&tables.problems, &mut tables.next_problem_id))
+    }
+
+    fn save_problem(&self, problem: &Problem) -> Result<()> {
+        let mut tables = self.tables.lock().unwrap();
+        tables.problems.insert(problem.id, problem.clone());
+        tables.config_problems.remove(&problem.id);
+        Ok(())
+    }
+
+    fn save_config_problem(&self, problem: &Problem) -> Result<bool> {
+        let mut tables = self.tables.lock().unwrap();
+        if let Some(stored) = tables.problems.get(&problem.id) {
+            // compared as they would be stored
+            let same = serde_json::to_value(stored).ok() == serde_json::to_value(problem).ok();
+            if !tables.config_problems.contains(&problem.id) && !same {
+                return Ok(false);
+            }
+        }
+        tables.problems.insert(problem.id, problem.clone());
+        tables.config_problems.insert(problem.id);
+        Ok(true)
+    }
+
+    fn delete_problem(&self, id: usize) -> Result<()> {
+        let mut tables = self.tables.lock().unwrap();
+        if tables.jobs.values().any(|x| x.submission.problem_id == id) {
+            return Err(StorageError::Invalid("Problem ".to_string() + &id.to_string() + " has submissions."));
+        }
+        if tables.contests.values().any(|x| x.problem_ids.contains(&id)) {
+            return Err(StorageError::Invalid("Problem ".to_string() + &id.to_string() + " is used by contests."));
+        }
+        tables.problems.remove(&id).ok_or(StorageError::NotFound)?;
+        tables.config_problems.remove(&id);
+        Ok(())
+    }
+
+    fn contests(&self) -> Result<Vec<Contest>> {
+        Ok(self.tables.lock().unwrap().contests.values().cloned().collect())
+    }
+
+    fn contest(&self, id: usize) -> Result<Contest> {
+        self.tables.lock().unwrap().contests.get(&id).cloned().ok_or(StorageError::NotFound)
+    }
+
+    fn insert_contest(&self, contest: &mut Contest) -> Result<()> {
+        let tables = &mut *self.tables.lock().unwrap();
+        contest.id = allocate_id(&tables.contests, &mut tables.next_contest_id);
+        tables.contests.insert(contest.id, contest.clone());
+        Ok(())
+    }
+
+    fn update_contest(&self, contest: &Contest) -> Result<()> {
+        let mut tables = self.tables.lock().unwrap();
+        match tables.contests.get_mut(&contest.id) {
+            Some(stored) => *stored = contest.clone(),
+            None => return Err(StorageError::NotFound)
+        }
+        Ok(())
+    }
+
+    fn jobs(&self, query: &QueryJob) -> Result<Vec<JobContent>> {
+        let (order_by, descending) = query.order().map_err(StorageError::Invalid)?;
+        let tables = self.tables.lock().unwrap();
+        let mut jobs: Vec<JobContent> = tables.jobs.values()
+            .filter(|job| {
+                let submission = &job.submission;
+                query.user_id.is_none_or(|x| x == submission.user_id)
+                    && query.user_name.as_ref().is_none_or(|name| {
+                        tables.users.get(&submission.user_id).is_some_and(|user| &user.name == name)
+                    })
+                    && query.contest_id.is_none_or(|x| x == submission.contest_id)
+                    && query.problem_id.is_none_or(|x| x == submission.problem_id)
+                    && query.language.as_ref().is_none_or(|x| x == &submission.language)
+                    && query.state.as_ref().is_none_or(|x| x == &job.state)
+                    && query.result.as_ref().is_none_or(|x| x == &job.result)
+                    && query.from.is_none_or(|x| job.created_time >= x)
+                    && query.to.is_none_or(|x| job.created_time <= x)
+            })
+            .cloned()
+            .collect();
+        jobs.sort_by(|a, b| {
+            let ordering = match order_by {
+                "created_time" => a.created_time.cmp(&b.created_time),
+                "updated_time" => a.updated_time.cmp(&b.updated_time),
+                "score" => a.score.partial_cmp(&b.score).unwrap_or(Ordering::Equal),
+                _ => Ordering::Equal
+            }.then(a.id.cmp(&b.id));
+            if descending { ordering.reverse() } else { ordering }
+        });
+        Ok(jobs.into_iter()
+            .skip(query.offset.unwrap_or(0))
+            .take(query.limit.unwrap_or(usize::MAX))
+            .map(|mut job| {
+                job.hide_stderr(query.admin, tables.problems.get(&job.submission.problem_id));
+                job
+            })
+            .collect())
+    }
+
+    fn job(&self, id: usize) -> Result<JobContent> {
+        self.tables.lock().unwrap().jobs.get(&id).cloned().ok_or(StorageError::NotFound)
+    }
+
+    fn insert_job(&self, job: &mut JobContent) -> Result<()> {
+        let tables = &mut *self.tables.lock().unwrap();
+        job.id = allocate_id(&tables.jobs, &mut tables.next_job_id);
+        tables.jobs.insert(job.id, job.clone());
+        Ok(())
+    }
+
+    fn transition_job(&self, job: &mut JobContent, from: &str, case_ids: Option<&[usize]>) -> Result<bool> {
+        let mut tables = self.tables.lock().unwrap();
+        let stored = tables.jobs.get_mut(&job.id).ok_or(StorageError::NotFound)?;
+        if stored.state != from || stored.version != job.version {
+            return Ok(false);
+        }
+        stored.copy_cases(job, case_ids);
+        job.updated_time = timestamp::now();
+        job.version += 1;
+        stored.state = job.state.clone();
+        stored.result = job.result.clone();
+        stored.score = job.score;
+        stored.updated_time = job.updated_time;
+        stored.version = job.version;
+        Ok(true)
+    }
+}
@@ -0,0 +1,58 @@
+use chrono::{DateTime, SubsecRound, Utc};
+use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+/// the format of every time the server writes, fixed width so that
+/// stored times compare as text
+const FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.3fZ";
+
+/// the current time, in milliseconds like stored times
+pub fn now() -> DateTime<Utc> {
+    Utc::now().trunc_subsecs(3)
+}
+
+pub fn format(time: &DateTime<Utc>) -> String {
+    time.format(FORMAT).to_string()
+}
+
+/// any RFC 3339 time, offsets are converted to UTC
+pub fn parse(time: &str) -> Result<DateTime<Utc>, String> {
+    DateTime::parse_from_rfc3339(time)
+        .map(|x| x.with_timezone(&Utc))
+        .map_err(|_| "Invalid time ".to_string() + time + ".")
+}
+
+/// read a time stored by `format`
+pub fn read(row: &rusqlite::Row, index: usize) -> rusqlite::Result<DateTime<Utc>> {
+    let time: String = row.get(index)?;
+    parse(&time).map_err(|message| {
+        rusqlite::Error::FromSqlConversionFailure(index, rusqlite::types::Type::Text, message.into())
+    })
+}
+
+/// for `#[serde(with = "timestamp")]`
+pub fn serialize<S: Serializer>(time: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format(time))
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DateTime<Utc>, D::Error> {
+    parse(&String::deserialize(deserializer)?).map_err(D::Error::custom)
+}
+
+/// for `#[serde(default, with = "timestamp::option")]`
+pub mod option {
+    use chrono::{DateTime, Utc};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(time: &Option<DateTime<Utc>>, serializer: S) -> Result<S::Ok, S::Error> {
+        match time {
+            Some(time) => super::serialize(time, serializer),
+            None => serializer.serialize_none()
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|time| super::parse(&time).map_err(D::Error::custom))
+            .transpose()
+    }
+}
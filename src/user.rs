@@ -1,7 +1,5 @@
 use serde::{Deserialize, Serialize};
 use actix_web::HttpResponse;
-use r2d2::{Pool, PooledConnection};
-use r2d2_sqlite::SqliteConnectionManager;
 use rusqlite::{params, Connection, Result};
 use chrono::*;
 use std::{
@@ -13,7 +11,10 @@ use lazy_static::lazy_static;
 use crate::{
     post, get, 
     web, Responder, 
-    ErrorMessage, job::JobContent
+    ErrorMessage, job::JobContent,
+    migration,
+    storage::Storage,
+    timestamp
 };
 
 // static user list 
@@ -33,24 +34,16 @@ pub struct User{
     pub name: String
 }
 impl User {
-    pub fn get_submisson_time(&self, job_list: &Vec<JobContent>) -> NaiveDateTime {
-        let s = "2022-08-27T02:05:29.000Z";
-        let fmt = "%Y-%m-%dT%H:%M:%S%.3fZ";
-        let mut latest_time: NaiveDateTime = NaiveDateTime::parse_from_str(s, fmt)
+    pub fn get_submisson_time(&self, job_list: &Vec<JobContent>) -> DateTime<Utc> {
+        let mut latest_time = timestamp::parse("2022-08-27T02:05:29.000Z")
         .expect("failed to get time");
         for job in job_list {
-            if job.submission.user_id == self.id {
-                let s = job.created_time.as_str();
-                let current_time: NaiveDateTime = NaiveDateTime::parse_from_str(s, fmt)
-                .expect("failed to get time");
-                if current_time > latest_time {
-                    latest_time = current_time;
-                }
+            if job.submission.user_id == self.id && job.created_time > latest_time {
+                latest_time = job.created_time;
             }
         }
         if self.get_submisson_count(job_list) == 0  {
-            let s = "3022-08-27T02:05:29.000Z";
-            latest_time = NaiveDateTime::parse_from_str(s, fmt)
+            latest_time = timestamp::parse("3022-08-27T02:05:29.000Z")
             .expect("failed to get time");
         }
         latest_time
@@ -73,12 +66,11 @@ impl User {
 #[allow(unreachable_code)]
 async fn post_users(
     body: web::Json<PostUser>,
-    pool: web::Data<Pool<SqliteConnectionManager>>
+    storage: web::Data<dyn Storage>
 ) -> impl Responder {
     log::info!("Post user {:?}", body.name);
 
-    let conn = pool.get().unwrap();
-    let user_list = query_users(&conn).unwrap();
+    let user_list = storage.users().unwrap();
     // check same name 
     for user in & user_list {
         if user.name == body.name {
@@ -94,13 +86,13 @@ async fn post_users(
     match body.id {
         Some(id) =>{
             // and check id
-            match query_user(&conn, id) {
+            match storage.user(id) {
                 Ok(_) => {
                     let user = User{
                         id: (id),
                         name: (body.name.clone())
                     };
-                    update_user(&conn, &user).unwrap();
+                    storage.update_user(&user).unwrap();
                     HttpResponse::Ok().json(user)
                 }
                 Err(_) => {
@@ -115,12 +107,12 @@ async fn post_users(
             }
         }
         None => {
-            // new user
-            let user = User{
-                id: (user_list.len()),
+            // new user, the id is allocated when it is inserted
+            let mut user = User{
+                id: (0),
                 name: (body.name.clone())
             };
-            insert_user(&conn, &user).unwrap();
+            storage.insert_user(&mut user).unwrap();
             HttpResponse::Ok().json(user)
         }
     }
@@ -132,29 +124,39 @@ async fn post_users(
 #[get("/users")]
 #[allow(unreachable_code)]
 async fn get_users(
-    pool: web::Data<Pool<SqliteConnectionManager>>
+    storage: web::Data<dyn Storage>
 ) -> impl Responder {
     log::info!("Get user");
-    let conn = pool.get().unwrap();
-    let user_list = query_users(&conn).unwrap();
+    let user_list = storage.users().unwrap();
     HttpResponse::Ok().json(user_list)
 }
 
 pub fn init_root_user(
-    conn: &PooledConnection<SqliteConnectionManager>
+    storage: &dyn Storage
 ) -> std::io::Result<()> {
-    let root = User{
+    let mut root = User{
         id: (0),
         name: ("root".to_string())
     };
-    let user_list = query_users(conn).unwrap();
-    if user_list.len() == 0 {
-        insert_user(conn, &root).unwrap();
+    let user_list = storage.users().unwrap();
+    if user_list.is_empty() {
+        storage.insert_user(&mut root).unwrap();
     }
     Ok(())
 }
 
-fn insert_user(conn: &Connection, user: &User) -> Result<()> {
+/// let the database allocate the id of the user and save it, root gets 0
+pub fn insert_user(conn: &Connection, user: &mut User) -> Result<()> {
+    conn.execute(
+        &format!("INSERT INTO users (id, name) VALUES ({}, ?)", migration::next_id_sql("users")),
+        params![user.name],
+    )?;
+    user.id = conn.last_insert_rowid() as usize;
+    Ok(())
+}
+
+/// save a user with the id as is
+pub fn save_user(conn: &Connection, user: &User) -> Result<()> {
     conn.execute(
         "INSERT INTO users (id, name) VALUES (?, ?)",
         params![user.id, user.name],
@@ -173,7 +175,7 @@ pub fn query_users(conn: &Connection) -> Result<Vec<User>> {
     users.collect()
 }
 
-fn update_user(conn: &Connection, user: &User) -> Result<()> {
+pub fn update_user(conn: &Connection, user: &User) -> Result<()> {
     conn.execute(
         "UPDATE users SET name = ? WHERE id = ?",
         params![user.name, user.id],
@@ -190,4 +192,43 @@ pub fn query_user(conn: &Connection, id: usize) -> Result<User> {
         })
     })?;
     users.next().unwrap_or(Err(rusqlite::Error::QueryReturnedNoRows))
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test, App};
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[actix_web::test]
+    async fn post_and_get_users_in_memory() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        init_root_user(storage.as_ref()).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(storage.clone()))
+                .service(post_users)
+                .service(get_users)
+        ).await;
+
+        let post = |body: Value| test::TestRequest::post().uri("/users").set_json(body).to_request();
+        let created: Value = test::call_and_read_body_json(&app, post(json!({"name": "alice"}))).await;
+        assert_eq!(created, json!({"id": 1, "name": "alice"}));
+
+        let response = test::call_service(&app, post(json!({"name": "alice"}))).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = test::call_service(&app, post(json!({"id": 2, "name": "bob"}))).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let renamed: Value = test::call_and_read_body_json(&app, post(json!({"id": 1, "name": "bob"}))).await;
+        assert_eq!(renamed, json!({"id": 1, "name": "bob"}));
+
+        let users: Value = test::call_and_read_body_json(
+            &app,
+            test::TestRequest::get().uri("/users").to_request()
+        ).await;
+        assert_eq!(users, json!([{"id": 0, "name": "root"}, {"id": 1, "name": "bob"}]));
+        assert_eq!(storage.users().unwrap().len(), 2);
+    }
+}
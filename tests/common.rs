@@ -4,6 +4,7 @@ use reqwest::blocking::{Client, RequestBuilder};
 use serde::{Deserialize, Serialize};
 use serde_json;
 use serde_json::Value;
+use std::collections::BTreeMap;
 use std::env::consts::EXE_EXTENSION;
 use std::fs::{File, OpenOptions};
 use std::io::Write;
@@ -73,6 +74,10 @@ struct TestRequest {
     path: String,
     method: String,
     content: Value,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    #[serde(default)]
+    body_file: Option<String>, // send the file as the body instead of content
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -116,6 +121,7 @@ pub struct TestCase {
     data: Vec<HttpComm>, // a sequence of HTTP requests and responses
     prefix: String,      // the prefix of the path of the HTTP requests
     running_process: Option<Child>,
+    envs: Vec<(String, String)>, // environment variables of the server
     stdout_file: PathBuf,
     stderr_file: PathBuf,
     http_file: PathBuf,
@@ -148,12 +154,61 @@ impl TestCase {
             data: serde_json::from_reader(File::open(data_file).unwrap()).unwrap(),
             prefix,
             running_process: None,
+            envs: Vec::new(),
             stdout_file,
             stderr_file,
             http_file,
         }
     }
 
+    /// start the server on the data already in the database
+    #[allow(dead_code)]
+    pub fn keep_data(mut self) -> Self {
+        self.arguments.retain(|x| x != "--flush-data");
+        self
+    }
+
+    /// start the server with another config file,
+    /// the requests still go to the address in the config of the case
+    #[allow(dead_code)]
+    pub fn config_file(mut self, path: &Path) -> Self {
+        let index = self.arguments.iter().position(|x| x == "--config").unwrap();
+        self.arguments[index + 1] = path.to_str().unwrap().to_string();
+        self
+    }
+
+    /// set an environment variable of the server
+    #[allow(dead_code)]
+    pub fn env(mut self, key: &str, value: &str) -> Self {
+        self.envs.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// run a command with the config of the case and return its stdout
+    #[allow(dead_code)]
+    pub fn command(&self, args: &[&str]) -> String {
+        let output = self.command_output(args);
+        assert!(
+            output.status.success(),
+            "case {} incorrect: command {:?} failed\n\n{}",
+            self.name,
+            args,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8_lossy(&output.stdout).to_string()
+    }
+
+    /// run a command with the config of the case, it may fail
+    #[allow(dead_code)]
+    pub fn command_output(&self, args: &[&str]) -> std::process::Output {
+        Command::new(EXE_PATH.as_os_str())
+            .args(self.arguments.iter().filter(|x| *x != "--flush-data"))
+            .args(args)
+            .envs(self.envs.iter().cloned())
+            .output()
+            .unwrap_or_else(|_| panic!("case {} incorrect: failed to execute command", self.name))
+    }
+
     fn log_and_send(
         &self,
         req: RequestBuilder,
@@ -229,6 +284,7 @@ impl TestCase {
 
         let command = Command::new(EXE_PATH.as_os_str())
             .args(&self.arguments)
+            .envs(self.envs.iter().cloned())
             .stdin(Stdio::piped())
             .stdout(Stdio::from(stdout))
             .stderr(Stdio::from(stderr))
@@ -293,8 +349,13 @@ impl TestCase {
             let mut request = CLIENT
                 .request(method.clone(), url)
                 .timeout(Duration::from_millis(c.timeout));
+            for (name, value) in &c.request.headers {
+                request = request.header(name, value);
+            }
             if let reqwest::Method::GET = method {
                 // no json body
+            } else if let Some(body_file) = &c.request.body_file {
+                request = request.body(std::fs::read(body_file).unwrap());
             } else {
                 request = request.json(&c.request.content);
             }
@@ -382,13 +443,22 @@ impl TestCase {
     }
 
     pub fn run(&mut self) -> Vec<Value> {
+        self.run_with(|_| ())
+    }
+
+    /// run the case, calling `hook` with the index of every request before it is sent
+    pub fn run_with(&mut self, mut hook: impl FnMut(usize)) -> Vec<Value> {
         self.start_server(false);
         // send requests sequentially
         let res = self
             .data
             .clone()
             .iter()
-            .map(|d| self.send_request_and_compare_response(d))
+            .enumerate()
+            .map(|(index, d)| {
+                hook(index);
+                self.send_request_and_compare_response(d)
+            })
             .collect();
         self.kill_server();
         res
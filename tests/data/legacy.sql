@@ -0,0 +1,34 @@
+-- a database written before schema migrations, problems were only in the config
+CREATE TABLE users (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL
+);
+CREATE TABLE contests (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    'from' TEXT NOT NULL,
+    'to' TEXT NOT NULL,
+    problem_ids TEXT NOT NULL,
+    user_ids TEXT NOT NULL,
+    submission_limit INTEGER NOT NULL
+);
+CREATE TABLE jobs (
+    id INTEGER PRIMARY KEY,
+    created_time TEXT NOT NULL,
+    updated_time TEXT NOT NULL,
+    source_code TEXT NOT NULL,
+    language TEXT NOT NULL,
+    user_id INTEGER NOT NULL,
+    contest_id INTEGER NOT NULL,
+    problem_id INTEGER NOT NULL,
+    state TEXT NOT NULL,
+    result TEXT NOT NULL,
+    score REAL NOT NULL,
+    cases TEXT NOT NULL
+);
+INSERT INTO users VALUES (0, 'root');
+INSERT INTO users VALUES (1, 'alice');
+INSERT INTO contests VALUES (1, 'Legacy', '2022-08-27T00:00:00.000Z', '2032-08-27T00:00:00.000Z', '0', '0,1', 3);
+INSERT INTO jobs VALUES (0, '2022-08-27T02:05:29.000Z', '2022-08-27T02:05:31.000Z', 'fn main() {let mut line1 = String::new();std::io::stdin().read_line(&mut line1).unwrap();let a: i32 = line1.trim().parse().unwrap();let mut line2 = String::new();std::io::stdin().read_line(&mut line2).unwrap();let b: i32 = line2.trim().parse().unwrap();println!("{}", a + b);}', 'Rust', 1, 1, 0,
+    'Finished', 'Wrong Answer', 10.0,
+    '0:Compilation Success:0:0:,1:Accepted:1000:0:,2:Wrong Answer:1000:0:first mismatch at line 1%2C column 1');
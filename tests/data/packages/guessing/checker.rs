@@ -0,0 +1,18 @@
+use std::{env, fs};
+
+fn read_number(path: &str) -> f64 {
+    fs::read_to_string(path).unwrap().trim().parse().unwrap()
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let output = read_number(&args[1]);
+    let answer = read_number(&args[2]);
+    if ((output - answer) / answer).abs() < 0.1 {
+        println!("Accepted");
+        println!("The error is within bounds");
+    } else {
+        println!("Wrong Answer");
+        println!("The error is beyond bounds");
+    }
+}
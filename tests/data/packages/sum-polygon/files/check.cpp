@@ -0,0 +1,27 @@
+// std::wcmp.cpp, compares sequences of tokens
+#include "testlib.h"
+
+using namespace std;
+
+int main(int argc, char *argv[]) {
+    setName("compare sequences of tokens");
+    registerTestlibCmd(argc, argv);
+
+    int n = 0;
+    string j, p;
+    while (!ans.seekEof() && !ouf.seekEof()) {
+        n++;
+        ans.readWordTo(j);
+        ouf.readWordTo(p);
+        if (j != p) {
+            quitf(_wa, "%d words differ - expected: '%s', found: '%s'", n, j.c_str(), p.c_str());
+        }
+    }
+    if (ans.seekEof() && ouf.seekEof()) {
+        quitf(_ok, "%d tokens", n);
+    }
+    if (ans.seekEof()) {
+        quitf(_wa, "Participant output contains extra tokens");
+    }
+    quitf(_wa, "Unexpected EOF in the participants output");
+}
@@ -0,0 +1,62 @@
+// the parts of testlib.h used by the checker of this package,
+// it exits with the codes of testlib: 0 ok, 1 wa, 2 pe, 3 fail
+#include <cctype>
+#include <cstdarg>
+#include <cstdio>
+#include <cstdlib>
+#include <string>
+
+enum TResult { _ok = 0, _wa = 1, _pe = 2, _fail = 3 };
+
+[[noreturn]] void quitf(TResult result, const char *format, ...) {
+    va_list args;
+    va_start(args, format);
+    std::vfprintf(stderr, format, args);
+    va_end(args);
+    std::fputc('\n', stderr);
+    std::exit(result);
+}
+
+struct InStream {
+    FILE *file = nullptr;
+
+    void open(const char *path) {
+        file = std::fopen(path, "r");
+        if (file == nullptr) {
+            quitf(_fail, "cannot open %s", path);
+        }
+    }
+
+    bool seekEof() {
+        int c;
+        while ((c = std::fgetc(file)) != EOF && std::isspace(c)) {
+        }
+        if (c == EOF) {
+            return true;
+        }
+        std::ungetc(c, file);
+        return false;
+    }
+
+    void readWordTo(std::string &word) {
+        word.clear();
+        seekEof();
+        int c;
+        while ((c = std::fgetc(file)) != EOF && !std::isspace(c)) {
+            word += static_cast<char>(c);
+        }
+    }
+};
+
+InStream inf, ouf, ans;
+
+void setName(const char *, ...) {}
+
+void registerTestlibCmd(int argc, char *argv[]) {
+    if (argc < 4) {
+        quitf(_fail, "usage: %s <input> <output> <answer>", argv[0]);
+    }
+    inf.open(argv[1]);
+    ouf.open(argv[2]);
+    ans.open(argv[3]);
+}
@@ -0,0 +1,453 @@
+mod common;
+use common::TestCase;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+
+/// pack the files under `dir` into a zip archive at `dest`
+fn zip_package(dir: &Path, dest: &Path) {
+    fn add_dir(zip: &mut zip::ZipWriter<File>, root: &Path, dir: &Path) {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                add_dir(zip, root, &path);
+            } else {
+                let name = path.strip_prefix(root).unwrap().to_str().unwrap();
+                zip.start_file(name, zip::write::FileOptions::default())
+                    .unwrap();
+                zip.write_all(&fs::read(&path).unwrap()).unwrap();
+            }
+        }
+    }
+    let mut zip = zip::ZipWriter::new(File::create(dest).unwrap());
+    add_dir(&mut zip, dir, dir);
+    zip.finish().unwrap();
+}
+
+#[test]
+fn test_ext_01_compile_cache() {
+    // submit the same code twice, then rejudge the first job
+    // check:
+    // 1. every job is judged as usual
+    // 2. only the first job compiles, the cache holds one artifact
+    let cache_dir = Path::new("target").join("ext_compile_cache");
+    fs::remove_dir_all(&cache_dir).ok();
+    TestCase::read("ext_01_compile_cache").run();
+
+    let artifacts = fs::read_dir(&cache_dir)
+        .unwrap()
+        .filter(|entry| {
+            !entry
+                .as_ref()
+                .unwrap()
+                .file_name()
+                .to_string_lossy()
+                .starts_with('.')
+        })
+        .count();
+    assert_eq!(
+        artifacts, 1,
+        "case ext_01_compile_cache incorrect: wrong number of artifacts"
+    );
+
+    let log = fs::read_to_string("tests/cases/ext_01_compile_cache.stderr").unwrap();
+    assert_eq!(
+        log.matches("Compile cache hit").count(),
+        2,
+        "case ext_01_compile_cache incorrect: the cache should be hit by the later jobs"
+    );
+}
+
+#[test]
+fn test_ext_02_stop_on_failure() {
+    // the cases after the first failed one are skipped,
+    // also when the cases run in parallel
+    TestCase::read("ext_02_stop_on_failure").run();
+}
+
+#[test]
+fn test_ext_03_output_limit() {
+    // print forever, with the limit of the case and with the global limit
+    // check that the run is stopped with Output Limit Exceeded
+    TestCase::read("ext_03_output_limit").run();
+}
+
+#[test]
+fn test_ext_04_base64_source() {
+    // submit base64 encoded source code, it is returned as submitted
+    // check that bad encodings and too large sources are rejected
+    TestCase::read("ext_04_base64_source").run();
+}
+
+#[test]
+fn test_ext_05_problem_crud() {
+    // create, read, update and delete problems through the API
+    // check:
+    // 1. only requests with the admin token change problems
+    // 2. jobs are judged with the saved cases
+    // 3. problems with submissions are not deleted
+    // 4. invalid problems are rejected, an update replaces all case files
+    let data_dir = Path::new("target").join("ext_problems");
+    fs::remove_dir_all(&data_dir).ok();
+    TestCase::read("ext_05_problem_crud").run();
+
+    let mut files: Vec<String> = fs::read_dir(data_dir.join("1"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .collect();
+    files.sort();
+    assert_eq!(
+        files,
+        ["1.ans", "1.in"],
+        "case ext_05_problem_crud incorrect: wrong case files after the update"
+    );
+    let staged = fs::read_dir(&data_dir)
+        .unwrap()
+        .filter(|entry| {
+            entry
+                .as_ref()
+                .unwrap()
+                .file_name()
+                .to_string_lossy()
+                .starts_with('.')
+        })
+        .count();
+    assert_eq!(
+        staged, 0,
+        "case ext_05_problem_crud incorrect: staged files left behind"
+    );
+}
+
+#[test]
+fn test_ext_06_problem_import() {
+    // upload a zip package with a checker written in Rust
+    // check:
+    // 1. only admins import packages
+    // 2. the checker is compiled and judges the jobs
+    // 3. other bodies are rejected
+    fs::remove_dir_all(Path::new("target").join("ext_packages")).ok();
+    zip_package(
+        &Path::new("tests")
+            .join("data")
+            .join("packages")
+            .join("guessing"),
+        &Path::new("target").join("ext_guessing.zip"),
+    );
+    TestCase::read("ext_06_problem_import").run();
+}
+
+#[test]
+fn test_ext_07_migrations() {
+    // migrate a database written before schema migrations
+    // check:
+    // 1. the migrate command lists and applies the pending migrations
+    // 2. jobs, contests and users are kept, new rows get new ids
+    let database = Path::new("target").join("ext_legacy.db");
+    fs::remove_file(&database).ok();
+    rusqlite::Connection::open(&database)
+        .unwrap()
+        .execute_batch(&fs::read_to_string("tests/data/legacy.sql").unwrap())
+        .unwrap();
+
+    let mut case = TestCase::read("ext_07_migrations").keep_data();
+    let status = case.command(&["migrate", "--status"]);
+    assert!(
+        status.contains("Schema version 0,") && status.contains("Pending 1: "),
+        "case ext_07_migrations incorrect: wrong status\n\n{}",
+        status
+    );
+    let applied = case.command(&["migrate"]);
+    assert!(
+        applied.contains("Applied 1: "),
+        "case ext_07_migrations incorrect: nothing applied\n\n{}",
+        applied
+    );
+    let status = case.command(&["migrate", "--status"]);
+    assert!(
+        !status.contains("Pending"),
+        "case ext_07_migrations incorrect: migrations left\n\n{}",
+        status
+    );
+    case.run();
+}
+
+#[test]
+fn test_ext_08_job_pagination() {
+    // list jobs a page at a time and in other orders
+    let results = TestCase::read("ext_08_job_pagination").run();
+    let lengths: Vec<usize> = results[3..9]
+        .iter()
+        .map(|jobs| jobs.as_array().unwrap().len())
+        .collect();
+    assert_eq!(
+        lengths,
+        [2, 1, 2, 3, 3, 1],
+        "case ext_08_job_pagination incorrect: wrong number of jobs"
+    );
+}
+
+#[test]
+fn test_ext_09_job_transitions() {
+    // rejudge a job while it runs, then twice in a row
+    // check:
+    // 1. only finished jobs are rejudged, a rejudge is saved before it returns
+    // 2. the creation time is kept and the update time moves on
+    let results = TestCase::read("ext_09_job_transitions").run();
+    assert_ne!(
+        results[5]["state"], "Finished",
+        "case ext_09_job_transitions incorrect: the rejudge was not saved"
+    );
+    assert_eq!(
+        results[6]["created_time"], results[2]["created_time"],
+        "case ext_09_job_transitions incorrect: the creation time changed"
+    );
+    assert!(
+        results[6]["updated_time"].as_str() > results[2]["updated_time"].as_str(),
+        "case ext_09_job_transitions incorrect: the update time did not move on"
+    );
+}
+
+#[test]
+fn test_ext_10_export_and_restore() {
+    // export a database, restore it into an empty one and back it up
+    // check that users, contests and jobs are the same after the restore
+    let archive = Path::new("target").join("ext_archive.jsonl");
+    let backup = Path::new("target").join("ext_backup.db");
+    let restored = Path::new("target").join("ext_restore.db");
+    for file in [&archive, &backup, &restored] {
+        fs::remove_file(file).ok();
+    }
+
+    let mut export = TestCase::read("ext_10_01_export");
+    export.run();
+    let output = export.command(&["export", archive.to_str().unwrap()]);
+    assert!(
+        output.starts_with("Exported"),
+        "case ext_10_01_export incorrect: wrong output\n\n{}",
+        output
+    );
+    export.command(&["backup", backup.to_str().unwrap()]);
+    let jobs: usize = rusqlite::Connection::open(&backup)
+        .unwrap()
+        .query_row("SELECT COUNT(*) FROM jobs", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(jobs, 1, "case ext_10_01_export incorrect: wrong backup");
+
+    let mut restore = TestCase::read("ext_10_02_restore").keep_data();
+    let output = restore.command(&["restore", archive.to_str().unwrap()]);
+    assert!(
+        output.starts_with("Restored"),
+        "case ext_10_02_restore incorrect: wrong output\n\n{}",
+        output
+    );
+    restore.run();
+}
+
+#[test]
+fn test_ext_11_bad_timestamps() {
+    // malformed times are rejected with 400, offsets are converted to UTC
+    let results = TestCase::read("ext_11_bad_timestamps").run();
+    assert_eq!(
+        results[4].as_array().unwrap().len(),
+        0,
+        "case ext_11_bad_timestamps incorrect: wrong number of jobs"
+    );
+}
+
+#[test]
+fn test_ext_12_empty_ranklist() {
+    // the ranklist of a contest without users is empty
+    let results = TestCase::read("ext_12_empty_ranklist").run();
+    assert_eq!(
+        results[1].as_array().unwrap().len(),
+        0,
+        "case ext_12_empty_ranklist incorrect: wrong number of users"
+    );
+}
+
+#[test]
+fn test_ext_13_config_problems() {
+    // change a problem of the config through the API, then reload the config
+    // check that the changed problem is kept and reported
+    TestCase::read("ext_13_config_problems").run();
+    let log = fs::read_to_string("tests/cases/ext_13_config_problems.stderr").unwrap();
+    assert!(
+        log.contains("Problem 0 was changed through the API"),
+        "case ext_13_config_problems incorrect: the kept problem was not reported"
+    );
+}
+
+#[test]
+fn test_ext_14_language_removed() {
+    // rejudge a job after its language was removed from the config
+    // check that it finishes with System Error instead of using another language
+    TestCase::read("ext_14_01_judged").run();
+    TestCase::read("ext_14_02_language_removed")
+        .keep_data()
+        .run();
+}
+
+#[test]
+fn test_ext_15_polygon_import() {
+    // upload a Polygon package with tests/%02d files, complete groups and wcmp
+    // check:
+    // 1. the checker is compiled with the language of its extension
+    // 2. tests of a complete group share its points and score only together
+    // 3. the testlib exit codes of the checker give the results
+    fs::remove_dir_all(Path::new("target").join("ext_polygon")).ok();
+    zip_package(
+        &Path::new("tests")
+            .join("data")
+            .join("packages")
+            .join("sum-polygon"),
+        &Path::new("target").join("ext_sum_polygon.zip"),
+    );
+    TestCase::read("ext_15_polygon_import").run();
+}
+
+#[test]
+fn test_ext_16_stderr() {
+    // submit a program printing to stderr to problems with and without show_stderr
+    // check that the stderr is kept, but only shown to admins without show_stderr
+    let results = TestCase::read("ext_16_stderr").run();
+    for (index, job) in [(2, &results[2]), (4, &results[4][1])] {
+        assert!(
+            job["cases"][1].get("stderr").is_none(),
+            "case ext_16_stderr incorrect: stderr shown in response {}",
+            index
+        );
+    }
+}
+
+#[test]
+fn test_ext_17_check_config() {
+    // check a valid config and one with several mistakes without starting the server
+    // check that every mistake is reported at once and the check fails
+    let output = TestCase::read("ext_17_01_valid_config").command(&["--check-config"]);
+    assert_eq!(
+        output, "Config is valid.\n",
+        "case ext_17_01_valid_config incorrect: wrong output"
+    );
+
+    let output = TestCase::read("ext_17_02_invalid_config").command_output(&["--check-config"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        !output.status.success(),
+        "case ext_17_02_invalid_config incorrect: the check passed"
+    );
+    for error in [
+        "server: invalid bind_port 70000",
+        "judge: workers must be positive",
+        "problem 0: duplicate id",
+        "problem 1 case 1: file ./tests/data/aplusb/missing.ans not found",
+        "language C: command has no %OUTPUT%",
+    ] {
+        assert!(
+            stderr.contains(error),
+            "case ext_17_02_invalid_config incorrect: {:?} not reported\n\n{}",
+            error,
+            stderr
+        );
+    }
+}
+
+#[test]
+fn test_ext_18_unix_socket() {
+    // leave a socket behind, then serve on the same path besides tcp
+    // check that the stale socket is replaced and requests over it are answered
+    let socket = Path::new("target").join("ext_oj.sock");
+    fs::remove_file(&socket).ok();
+    drop(UnixListener::bind(&socket).unwrap());
+
+    TestCase::read("ext_18_unix_socket").run_with(|index| {
+        if index != 1 {
+            return;
+        }
+        let mut stream = UnixStream::connect(&socket).unwrap();
+        stream
+            .write_all(b"GET /users HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 200") && response.contains(r#"{"id":1,"name":"alice"}"#),
+            "case ext_18_unix_socket incorrect: wrong response over the socket\n\n{}",
+            response
+        );
+    });
+}
+
+#[test]
+fn test_ext_19_reload() {
+    // add a problem to the config and reload it, then reload a broken config
+    // check:
+    // 1. only admins reload the config
+    // 2. the new problem is served and judged after the reload
+    // 3. a broken config is rejected and the loaded one stays
+    let case_config = "tests/cases/ext_19_reload.config.json";
+    let config_file = Path::new("target").join("ext_reload.config.json");
+    fs::copy(case_config, &config_file).unwrap();
+    let mut config: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(case_config).unwrap()).unwrap();
+    let mut problem = config["problems"][0].clone();
+    problem["id"] = 1.into();
+    problem["name"] = "aplusb-reloaded".into();
+
+    TestCase::read("ext_19_reload")
+        .config_file(&config_file)
+        .run_with(|index| {
+            let problems = config["problems"].as_array_mut().unwrap();
+            match index {
+                // the second copy duplicates the id
+                2 | 5 => problems.push(problem.clone()),
+                _ => return,
+            }
+            fs::write(&config_file, config.to_string()).unwrap();
+        });
+}
+
+#[test]
+fn test_ext_20_config_formats() {
+    // start the server with the same config in toml and in yaml,
+    // the port and the admin token of the files are replaced by OJ_* variables
+    // check that the jobs are judged and the variables win over the files
+    for format in ["toml", "yaml"] {
+        TestCase::read("ext_20_config_formats")
+            .config_file(
+                &Path::new("tests")
+                    .join("data")
+                    .join("configs")
+                    .join(format!("ext.{}", format)),
+            )
+            .env("OJ_BIND_PORT", "12345")
+            .env("OJ_ADMIN_TOKEN", "s3cret")
+            .run();
+    }
+}
+
+#[test]
+fn test_ext_21_database_path() {
+    // keep the database under directories that do not exist yet, then in memory
+    // check:
+    // 1. the directories are created and the data survives a restart
+    // 2. :memory: is shared by all connections, leaves no file and is gone after a restart
+    let database_dir = Path::new("target").join("ext_database");
+    fs::remove_dir_all(&database_dir).ok();
+    TestCase::read("ext_21_01_database_dir").run();
+    assert!(
+        database_dir.join("nested").join("oj.db").is_file(),
+        "case ext_21_01_database_dir incorrect: no database file"
+    );
+
+    let results = TestCase::read("ext_21_02_memory_database").run();
+    assert_eq!(
+        results[4].as_array().unwrap().len(),
+        1,
+        "case ext_21_02_memory_database incorrect: users kept after a restart"
+    );
+    assert!(
+        !Path::new(":memory:").exists(),
+        "case ext_21_02_memory_database incorrect: a file named :memory: was created"
+    );
+}
@@ -0,0 +1,43 @@
+mod common;
+use common::TestCase;
+
+// cases of the basic and advanced requirements,
+// run again with everything kept in memory
+
+fn read(name: &str) -> TestCase {
+    TestCase::read(name).env("OJ_DATABASE_BACKEND", "memory")
+}
+
+#[test]
+fn test_mem_01_job_list() {
+    // list and filter jobs
+    read("03_01_job_list").run();
+    read("03_02_job_list_with_filter").run();
+}
+
+#[test]
+fn test_mem_02_rejudging() {
+    // a rejudge moves the job from Finished back to Queueing
+    read("03_03_rejudging").run();
+}
+
+#[test]
+fn test_mem_03_users_and_ranklist() {
+    read("04_01_user_support").run();
+    read("05_01_global_ranklist").run();
+    read("05_02_scoring_rule").run();
+    read("05_03_tie_breaker").run();
+}
+
+#[test]
+fn test_mem_04_contest_support() {
+    // contests, their users and the ranklist
+    read("adv_01_contest_support").run();
+}
+
+#[test]
+fn test_mem_05_packed_judging() {
+    // cases of a failed group are skipped, so only some cases are saved
+    // by each transition
+    read("adv_05_packed_judging").run();
+}